@@ -0,0 +1,76 @@
+//! InMemoryVectorStoreの総当たり検索のベンチマーク
+//!
+//! `naive`は以前の実装(全レコードをcloneし、毎回両方のノルムを計算し、全件をソートする)を再現したもの
+//!
+//! ```sh
+//! cargo bench --bench similarity_search
+//! ```
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use langchain::embeddings::FakeEmbeddings;
+use langchain::schema::Document;
+use langchain::vectorstores::*;
+
+const DIMENSION: usize = 1536;
+const K: usize = 4;
+
+fn naive_cosine_similarity(a: &Vec<f32>, b: &Vec<f32>) -> f32 {
+    let dot =
+        |a: &Vec<f32>, b: &Vec<f32>| -> f32 { a.iter().zip(b.iter()).map(|(a, b)| a * b).sum() };
+    dot(a, b) / (dot(a, a).sqrt() * dot(b, b).sqrt())
+}
+
+fn naive_search(records: &[VectorRecord], query: &Vec<f32>, k: usize) -> Vec<Document> {
+    let mut scored: Vec<(VectorRecord, f32)> = records
+        .iter()
+        .cloned()
+        .map(|record| {
+            let similarity = naive_cosine_similarity(query, &record.embedding);
+            (record, similarity)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    scored
+        .into_iter()
+        .take(k)
+        .map(|(record, _)| record.document)
+        .collect()
+}
+
+fn bench_similarity_search(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("similarity_search");
+    group.sample_size(20);
+    for n in [1_000, 10_000, 50_000] {
+        let documents: Vec<Document> = (0..n)
+            .map(|i| Document::new(&format!("doc-{}", i), i))
+            .collect();
+        let store = runtime
+            .block_on(InMemoryVectorStore::from_document(
+                documents,
+                FakeEmbeddings::new(DIMENSION),
+            ))
+            .unwrap();
+        let query = FakeEmbeddings::new(DIMENSION).embed_one("query");
+        let records: Vec<VectorRecord> = runtime
+            .block_on(store.similarity_search_records_by_vector(&query, Some(n), None, None))
+            .unwrap()
+            .into_iter()
+            .map(|(record, _)| record)
+            .collect();
+
+        group.bench_with_input(BenchmarkId::new("naive", n), &n, |b, _| {
+            b.iter(|| naive_search(black_box(&records), black_box(&query), K))
+        });
+        group.bench_with_input(BenchmarkId::new("inmemory", n), &n, |b, _| {
+            b.iter(|| {
+                runtime
+                    .block_on(store.similarity_search_by_vector(black_box(&query), Some(K), None))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_similarity_search);
+criterion_main!(benches);
@@ -20,7 +20,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     // retrieval
     let question = "What are the approaches to Task Decomposition?";
-    let docs = store.similarity_search(question, None).await?;
+    let docs = store.similarity_search(question, None, None).await?;
     println!("docs: {}", docs.len());
     Ok(())
 }
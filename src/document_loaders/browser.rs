@@ -65,12 +65,12 @@ impl<'a> Loader for BrowserURLLoader<'a> {
             tab.wait_until_navigated()?;
             // TODO: ここは後で直す bodyをとりたい
             let text = tab.wait_for_element("body")?.get_content()?;
-            docs.push(Document {
-                page_content: text,
-                lookup_str: "".to_string(),
-                lookup_index: index,
-                metadata: None,
-            });
+            let title = tab.get_title()?;
+            docs.push(
+                Document::new(&text, index)
+                    .with_metadata("source", *url)
+                    .with_metadata("title", title),
+            );
         }
         Ok(docs)
     }
@@ -97,6 +97,7 @@ mod tests {
         );
         let docs = loader.load().await?;
         assert_eq!(docs.len(), 1);
+        assert_eq!(docs[0].metadata["source"], "https://www.google.com");
         dbg!(&docs);
         Ok(())
     }
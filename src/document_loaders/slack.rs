@@ -63,9 +63,7 @@ impl<'a> Loader for SlackLoader<'a> {
                 .messages
                 .into_iter()
                 .enumerate()
-                .map(|(index, message)| {
-                    Document::new(&message.content.text.unwrap_or("".into()), index)
-                })
+                .map(|(index, message)| message_to_document(message, places[0], index))
                 .collect());
         };
         let reply = session
@@ -82,13 +80,29 @@ impl<'a> Loader for SlackLoader<'a> {
             .messages
             .into_iter()
             .enumerate()
-            .map(|(index, message)| {
-                Document::new(&message.content.text.unwrap_or("".into()), index)
-            })
+            .map(|(index, message)| message_to_document(message, places[0], index))
             .collect())
     }
 }
 
+/// Slackのメッセージをmetadata付きのDocumentに変換する
+///
+/// metadata: `source`, `channel`, `ts`, `thread_ts`(threadの場合のみ), `user`(取得できた場合のみ)
+fn message_to_document(message: SlackHistoryMessage, channel: &str, index: usize) -> Document {
+    let ts = message.origin.ts.to_string();
+    let mut doc = Document::new(&message.content.text.unwrap_or_default(), index)
+        .with_metadata("source", format!("slack://{}/{}", channel, ts))
+        .with_metadata("channel", channel)
+        .with_metadata("ts", ts);
+    if let Some(thread_ts) = message.origin.thread_ts {
+        doc = doc.with_metadata("thread_ts", thread_ts.to_string());
+    }
+    if let Some(user) = message.sender.user {
+        doc = doc.with_metadata("user", user.to_string());
+    }
+    doc
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -102,6 +116,9 @@ mod tests {
         let token = config_env_var("SLACK_TOKEN")?;
         let loader = SlackLoader::new(&token, "C017Y386TNK-1690187559.790209", None, None);
         let docs = loader.load().await?;
+        assert!(docs
+            .iter()
+            .all(|doc| doc.metadata["channel"] == "C017Y386TNK"));
         dbg!(docs);
 
         Ok(())
@@ -0,0 +1,347 @@
+use super::Embeddings;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// # ByteStore
+///
+/// keyに対応するバイト列を保存する場所。`CachedEmbeddings`がembeddingを保存するのに使う
+#[async_trait::async_trait]
+pub trait ByteStore: Send + Sync + 'static {
+    /// `keys`と同じ順序で値を返す。無いkeyはNone
+    async fn mget(&self, keys: &[String]) -> anyhow::Result<Vec<Option<Vec<u8>>>>;
+    /// 既にあるkeyは上書きする
+    async fn mset(&self, items: Vec<(String, Vec<u8>)>) -> anyhow::Result<()>;
+}
+
+/// # InMemoryByteStore
+///
+/// プロセスが終了すると消える
+#[derive(Debug, Default)]
+pub struct InMemoryByteStore {
+    values: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+impl InMemoryByteStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait::async_trait]
+impl ByteStore for InMemoryByteStore {
+    async fn mget(&self, keys: &[String]) -> anyhow::Result<Vec<Option<Vec<u8>>>> {
+        let values = self.values.read().unwrap_or_else(PoisonError::into_inner);
+        Ok(keys.iter().map(|key| values.get(key).cloned()).collect())
+    }
+
+    async fn mset(&self, items: Vec<(String, Vec<u8>)>) -> anyhow::Result<()> {
+        let mut values = self.values.write().unwrap_or_else(PoisonError::into_inner);
+        values.extend(items);
+        Ok(())
+    }
+}
+
+/// # LocalFileStore
+///
+/// `root`の下にkeyごとに1ファイルで保存する。keyはファイル名に使える文字だけにすること
+#[derive(Debug, Clone)]
+pub struct LocalFileStore {
+    root: PathBuf,
+}
+
+impl LocalFileStore {
+    /// `root`が無ければ作る
+    pub fn new(root: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            || key.starts_with('.')
+        {
+            anyhow::bail!("invalid key for LocalFileStore: {:?}", key);
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait::async_trait]
+impl ByteStore for LocalFileStore {
+    async fn mget(&self, keys: &[String]) -> anyhow::Result<Vec<Option<Vec<u8>>>> {
+        keys.iter()
+            .map(|key| match std::fs::read(self.path(key)?) {
+                Ok(value) => Ok(Some(value)),
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(error) => Err(error.into()),
+            })
+            .collect()
+    }
+
+    /// 一時ファイルに書き込んでからrenameするので、書き込み途中のファイルを読むことはない
+    async fn mset(&self, items: Vec<(String, Vec<u8>)>) -> anyhow::Result<()> {
+        for (key, value) in items {
+            let path = self.path(&key)?;
+            let tmp = self
+                .root
+                .join(format!(".{}.{}.tmp", key, uuid::Uuid::new_v4()));
+            std::fs::write(&tmp, value)?;
+            std::fs::rename(&tmp, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// # CachedEmbeddings
+///
+/// embeddingをテキスト(documentの場合は`page_content`)と`Embeddings::identity`のハッシュをkeyにして`store`に保存し、
+/// 保存されていないテキストだけを`embeddings`に渡す。同じ内容を再度indexする際の費用を減らすためのもの
+///
+/// queryは使い回すことが少ないのでキャッシュしない
+///
+/// cloneしたものは同じ`store`を共有する
+///
+/// ```no_run
+/// # use langchain::embeddings::{CachedEmbeddings, LocalFileStore, OpenAIEmbedding};
+/// let embeddings = CachedEmbeddings::new(
+///     OpenAIEmbedding::default(),
+///     LocalFileStore::new("./embedding_cache")?,
+/// );
+/// # anyhow::Ok(())
+/// ```
+pub struct CachedEmbeddings<E: Embeddings, S: ByteStore = InMemoryByteStore> {
+    embeddings: E,
+    store: Arc<S>,
+}
+
+// deriveすると`S: Clone`も必要になるので手で実装する
+impl<E: Embeddings + Clone, S: ByteStore> Clone for CachedEmbeddings<E, S> {
+    fn clone(&self) -> Self {
+        Self {
+            embeddings: self.embeddings.clone(),
+            store: self.store.clone(),
+        }
+    }
+}
+
+impl<E: Embeddings, S: ByteStore> CachedEmbeddings<E, S> {
+    pub fn new(embeddings: E, store: S) -> Self {
+        Self {
+            embeddings,
+            store: Arc::new(store),
+        }
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.embeddings
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+}
+
+/// モデルや設定が違うembeddingを取り違えないよう、`identity`もハッシュに含める
+fn key(identity: &str, text: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(identity.as_bytes());
+    hasher.update([0]);
+    hasher.update(text.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+fn encode(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn decode(bytes: &[u8]) -> anyhow::Result<Vec<f32>> {
+    let chunks = bytes.chunks_exact(4);
+    if !chunks.remainder().is_empty() {
+        anyhow::bail!("cached embedding has invalid length: {} bytes", bytes.len());
+    }
+    Ok(chunks
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect())
+}
+
+#[async_trait::async_trait]
+impl<E: Embeddings, S: ByteStore> Embeddings for CachedEmbeddings<E, S> {
+    async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let identity = self.embeddings.identity();
+        let keys: Vec<String> = texts.iter().map(|text| key(&identity, text)).collect();
+        let mut embeddings = self
+            .store
+            .mget(&keys)
+            .await?
+            .into_iter()
+            .map(|value| value.map(|bytes| decode(&bytes)).transpose())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // 同じテキストが複数あっても1回だけ渡す
+        let mut misses: HashMap<&str, usize> = HashMap::new();
+        let mut missing = Vec::new();
+        for (i, text) in texts.iter().enumerate() {
+            if embeddings[i].is_none() && !misses.contains_key(keys[i].as_str()) {
+                misses.insert(&keys[i], missing.len());
+                missing.push(*text);
+            }
+        }
+        if !missing.is_empty() {
+            let computed = self.embeddings.embed_texts(&missing).await?;
+            if computed.len() != missing.len() {
+                anyhow::bail!(
+                    "expected {} embeddings, but got {}",
+                    missing.len(),
+                    computed.len()
+                );
+            }
+            for (i, key) in keys.iter().enumerate() {
+                if let Some(&j) = misses.get(key.as_str()) {
+                    embeddings[i] = Some(computed[j].clone());
+                }
+            }
+            let items = missing
+                .iter()
+                .zip(&computed)
+                .map(|(text, embedding)| (key(&identity, text), encode(embedding)))
+                .collect();
+            self.store.mset(items).await?;
+        }
+        Ok(embeddings
+            .into_iter()
+            .map(Option::unwrap_or_default)
+            .collect())
+    }
+
+    async fn embed_query(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        self.embeddings.embed_query(text).await
+    }
+
+    fn model_name(&self) -> &str {
+        self.embeddings.model_name()
+    }
+
+    fn dimension(&self) -> Option<usize> {
+        self.embeddings.dimension()
+    }
+
+    fn identity(&self) -> String {
+        self.embeddings.identity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::fake::RecordingEmbeddings;
+    use crate::embeddings::FakeEmbeddings;
+    use crate::schema::Document;
+    use crate::vectorstores::{InMemoryVectorStore, VectorStore};
+
+    fn documents(texts: &[&str]) -> Vec<Document> {
+        texts
+            .iter()
+            .enumerate()
+            .map(|(i, text)| Document::new(text, i))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_only_misses_are_embedded() -> anyhow::Result<()> {
+        let cached =
+            CachedEmbeddings::new(RecordingEmbeddings::default(), InMemoryByteStore::new());
+        assert_eq!(
+            cached.embed_document(&documents(&["a", "bb", "a"])).await?,
+            vec![vec![1.0], vec![2.0], vec![1.0]]
+        );
+        assert_eq!(
+            cached
+                .embed_document(&documents(&["ccc", "bb", "dddd", "a"]))
+                .await?,
+            vec![vec![3.0], vec![2.0], vec![4.0], vec![1.0]]
+        );
+        assert_eq!(
+            cached.inner().calls(),
+            vec![vec!["a", "bb"], vec!["ccc", "dddd"]]
+        );
+        assert_eq!(cached.store().len(), 4);
+
+        cached.embed_document(&documents(&["a", "dddd"])).await?;
+        assert_eq!(cached.inner().calls().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_local_file_store() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("langchain-test-{}", uuid::Uuid::new_v4()));
+        let cached =
+            CachedEmbeddings::new(RecordingEmbeddings::default(), LocalFileStore::new(&root)?);
+        cached.embed_document(&documents(&["a", "bb"])).await?;
+
+        // 別のインスタンスからも読める
+        let cached =
+            CachedEmbeddings::new(RecordingEmbeddings::default(), LocalFileStore::new(&root)?);
+        assert_eq!(
+            cached.embed_document(&documents(&["bb", "a"])).await?,
+            vec![vec![2.0], vec![1.0]]
+        );
+        assert!(cached.inner().calls().is_empty());
+        assert!(cached.store().mget(&["../a".to_string()]).await.is_err());
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_identity_is_part_of_key() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("langchain-test-{}", uuid::Uuid::new_v4()));
+        let texts = ["a", "b"];
+        let fake = FakeEmbeddings::new(4);
+        let cached = CachedEmbeddings::new(fake, LocalFileStore::new(&root)?);
+        assert_eq!(
+            cached.embed_texts(&texts).await?,
+            fake.embed_texts(&texts).await?
+        );
+
+        // model_nameは同じ"fake"だが、次元数やseedが違えば別のembeddingとして扱う
+        for other in [FakeEmbeddings::new(8), fake.with_seed(1)] {
+            let cached = CachedEmbeddings::new(other, LocalFileStore::new(&root)?);
+            assert_eq!(
+                cached.embed_texts(&texts).await?,
+                other.embed_texts(&texts).await?
+            );
+        }
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_with_vector_store() -> anyhow::Result<()> {
+        let cached =
+            CachedEmbeddings::new(RecordingEmbeddings::default(), InMemoryByteStore::new());
+        let store =
+            InMemoryVectorStore::from_document(documents(&["a", "bb"]), cached.clone()).await?;
+        // storeを共有しているので、元のCachedEmbeddingsからも保存したembeddingが見える
+        assert_eq!(cached.store().len(), 2);
+        cached.embed_document(&documents(&["bb"])).await?;
+        assert_eq!(cached.inner().calls(), vec![vec!["a", "bb"]]);
+        assert_eq!(store.similarity_search("a", Some(1), None).await?.len(), 1);
+        Ok(())
+    }
+}
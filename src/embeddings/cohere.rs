@@ -0,0 +1,199 @@
+use super::http::{self, RequestSettings};
+use super::Embeddings;
+
+/// # CohereEmbedding
+///
+/// Cohereの`/v1/embed`でembeddingを生成する
+///
+/// v3のモデルは入力の用途の指定が必要なので、documentには`document_input_type`、
+/// queryには`query_input_type`を送る
+#[derive(Debug, Clone)]
+pub struct CohereEmbedding {
+    pub model: String,
+    /// デフォルトは`https://api.cohere.com/v1`
+    pub api_base: String,
+    /// デフォルトは環境変数`COHERE_API_KEY`の値。空の場合は`Authorization`ヘッダーを送らない
+    pub api_key: String,
+    /// デフォルトは`search_document`
+    pub document_input_type: String,
+    /// デフォルトは`search_query`
+    pub query_input_type: String,
+    /// 最大長を超える入力の扱い。`NONE`, `START`, `END`のいずれか。`NONE`の場合はエラーになる
+    pub truncate: String,
+    /// `batch_size`の上限は96
+    pub request: RequestSettings,
+}
+
+impl CohereEmbedding {
+    pub fn new(model: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            api_base: "https://api.cohere.com/v1".to_string(),
+            api_key: std::env::var("COHERE_API_KEY").unwrap_or_default(),
+            document_input_type: "search_document".to_string(),
+            query_input_type: "search_query".to_string(),
+            truncate: "END".to_string(),
+            request: RequestSettings::new(96, 4, 6),
+        }
+    }
+
+    async fn embed_batch(&self, texts: &[&str], input_type: &str) -> anyhow::Result<Vec<Vec<f32>>> {
+        let mut request = self
+            .request
+            .client
+            .post(format!("{}/embed", self.api_base.trim_end_matches('/')))
+            .json(&EmbedRequest {
+                model: &self.model,
+                texts,
+                input_type,
+                truncate: &self.truncate,
+            });
+        if !self.api_key.is_empty() {
+            request = request.bearer_auth(&self.api_key);
+        }
+        let response = self.request.send(request).await?;
+        let embeddings = response.json::<EmbedResponse>().await?.embeddings;
+        http::check_len(texts.len(), &embeddings)?;
+        Ok(embeddings)
+    }
+
+    async fn embed_inputs(
+        &self,
+        texts: &[&str],
+        input_type: &str,
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.request
+            .embed_in_batches(texts, |batch| self.embed_batch(batch, input_type))
+            .await
+    }
+}
+
+#[derive(serde::Serialize)]
+struct EmbedRequest<'a> {
+    model: &'a str,
+    texts: &'a [&'a str],
+    input_type: &'a str,
+    truncate: &'a str,
+}
+
+#[derive(serde::Deserialize)]
+struct EmbedResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+#[async_trait::async_trait]
+impl Embeddings for CohereEmbedding {
+    async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.embed_inputs(texts, &self.document_input_type).await
+    }
+
+    async fn embed_query(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        self.embed_inputs(&[text], &self.query_input_type)
+            .await?
+            .pop()
+            .ok_or(anyhow::anyhow!("no embedding was returned for the query"))
+    }
+
+    fn model_name(&self) -> &str {
+        &self.model
+    }
+
+    fn dimension(&self) -> Option<usize> {
+        match self.model.as_str() {
+            "embed-english-v3.0" | "embed-multilingual-v3.0" => Some(1024),
+            "embed-english-light-v3.0" | "embed-multilingual-light-v3.0" => Some(384),
+            _ => None,
+        }
+    }
+
+    fn identity(&self) -> String {
+        format!(
+            "{}(input_type={},truncate={})",
+            self.model, self.document_input_type, self.truncate
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::http::fixture;
+    use wiremock::matchers::{body_json, header, header_exists, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_embed_with_fixture() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/embed"))
+            .and(header("authorization", "Bearer co-key"))
+            .and(body_json(serde_json::json!({
+                "model": "embed-english-v3.0",
+                "texts": ["hello", "goodbye"],
+                "input_type": "search_document",
+                "truncate": "END",
+            })))
+            .respond_with(fixture(include_str!(
+                "../../tests/fixtures/embeddings/cohere_embed.json"
+            )))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/embed"))
+            .and(body_json(serde_json::json!({
+                "model": "embed-english-v3.0",
+                "texts": ["greeting"],
+                "input_type": "search_query",
+                "truncate": "END",
+            })))
+            .respond_with(fixture(include_str!(
+                "../../tests/fixtures/embeddings/cohere_embed_query.json"
+            )))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let embedding = CohereEmbedding {
+            api_base: format!("{}/v1", server.uri()),
+            api_key: "co-key".to_string(),
+            ..CohereEmbedding::new("embed-english-v3.0")
+        };
+        let result = embedding.embed_texts(&["hello", "goodbye"]).await?;
+        assert_eq!(
+            result,
+            vec![
+                vec![0.0166, -0.0347, 0.0521, -0.0093],
+                vec![-0.0275, 0.0418, 0.0139, 0.0362],
+            ]
+        );
+        assert_eq!(
+            embedding.embed_query("greeting").await?,
+            vec![0.0094, -0.0212, 0.0487, 0.0055]
+        );
+        assert_eq!(embedding.dimension(), Some(1024));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_no_authorization_without_api_key() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header_exists("authorization"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(fixture(include_str!(
+                "../../tests/fixtures/embeddings/cohere_embed_query.json"
+            )))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let embedding = CohereEmbedding {
+            api_base: server.uri(),
+            api_key: String::new(),
+            ..CohereEmbedding::new("embed-english-v3.0")
+        };
+        assert_eq!(embedding.embed_texts(&["greeting"]).await?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+use super::Embeddings;
+
+/// FNV-1aのハッシュ
+fn fnv1a(seed: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64 ^ seed, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100_0000_01b3)
+        })
+}
+
+/// # FakeEmbeddings
+///
+/// テキストと`seed`のハッシュから作った疑似乱数のベクトルを返す。ネットワークを使わずにテストするためのもの
+///
+/// 同じテキストには常に同じベクトルを返すが、ベクトル同士の近さに意味はない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FakeEmbeddings {
+    pub dimension: usize,
+    pub seed: u64,
+}
+
+impl FakeEmbeddings {
+    pub fn new(dimension: usize) -> Self {
+        Self { dimension, seed: 0 }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// 各要素は[-1, 1)の範囲
+    pub fn embed_one(&self, text: &str) -> Vec<f32> {
+        let mut state = fnv1a(self.seed, text.as_bytes());
+        (0..self.dimension)
+            .map(|_| {
+                // xorshift
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state % 2000) as f32 / 1000.0 - 1.0
+            })
+            .collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl Embeddings for FakeEmbeddings {
+    async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        Ok(texts.iter().map(|text| self.embed_one(text)).collect())
+    }
+
+    fn model_name(&self) -> &str {
+        "fake"
+    }
+
+    fn dimension(&self) -> Option<usize> {
+        Some(self.dimension)
+    }
+
+    fn identity(&self) -> String {
+        format!("fake(dimension={},seed={})", self.dimension, self.seed)
+    }
+}
+
+/// # HashingEmbeddings
+///
+/// 単語をハッシュで`dimension`個の次元に割り振って数えたもの(feature hashing)をノルム1にして返す
+///
+/// 同じ単語を多く含むテキスト同士ほど近くなるので、ネットワークを使わずに検索の結果を確かめられる。
+/// 空白や記号で区切り、小文字にしたものを単語とする
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashingEmbeddings {
+    pub dimension: usize,
+}
+
+impl HashingEmbeddings {
+    pub fn new(dimension: usize) -> Self {
+        Self { dimension }
+    }
+
+    pub fn embed_one(&self, text: &str) -> Vec<f32> {
+        let mut embedding = vec![0.0f32; self.dimension];
+        if self.dimension == 0 {
+            return embedding;
+        }
+        for word in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+        {
+            let hash = fnv1a(0, word.to_lowercase().as_bytes());
+            // 衝突した単語同士が打ち消し合うよう、ハッシュの最上位ビットで符号を決める
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            embedding[(hash % self.dimension as u64) as usize] += sign;
+        }
+        let norm = crate::vectorstores::utils::norm(&embedding);
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|v| *v /= norm);
+        }
+        embedding
+    }
+}
+
+#[async_trait::async_trait]
+impl Embeddings for HashingEmbeddings {
+    async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        Ok(texts.iter().map(|text| self.embed_one(text)).collect())
+    }
+
+    fn model_name(&self) -> &str {
+        "hashing"
+    }
+
+    fn dimension(&self) -> Option<usize> {
+        Some(self.dimension)
+    }
+}
+
+/// 渡されたテキストを呼び出しごとに記録し、バイト数を1次元のembeddingとして返すテスト用のEmbeddings
+///
+/// cloneしたものは記録を共有する
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct RecordingEmbeddings {
+    calls: std::sync::Arc<std::sync::Mutex<Vec<Vec<String>>>>,
+}
+
+#[cfg(test)]
+impl RecordingEmbeddings {
+    /// `embed_texts`の呼び出しごとに渡されたテキスト
+    pub(crate) fn calls(&self) -> Vec<Vec<String>> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// これまでに渡されたテキストを順につなげたもの
+    pub(crate) fn texts(&self) -> Vec<String> {
+        self.calls().concat()
+    }
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl Embeddings for RecordingEmbeddings {
+    async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(texts.iter().map(|text| text.to_string()).collect());
+        Ok(texts.iter().map(|text| vec![text.len() as f32]).collect())
+    }
+
+    fn model_name(&self) -> &str {
+        "recording"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vectorstores::utils::cosine_similarity;
+
+    #[test]
+    fn test_fake_embeddings_are_deterministic() {
+        let fake = FakeEmbeddings::new(16);
+        assert_eq!(fake.embed_one("hello"), fake.embed_one("hello"));
+        assert_eq!(fake.embed_one("hello").len(), 16);
+        assert_ne!(fake.embed_one("hello"), fake.embed_one("world"));
+        assert_ne!(
+            fake.embed_one("hello"),
+            fake.with_seed(1).embed_one("hello")
+        );
+        assert!(fake
+            .embed_one("hello")
+            .iter()
+            .all(|v| (-1.0..1.0).contains(v)));
+    }
+
+    #[test]
+    fn test_hashing_embeddings_similarity() {
+        let hashing = HashingEmbeddings::new(256);
+        let query = hashing.embed_one("How do I bake bread?");
+        let related = hashing.embed_one("Bake the bread for 40 minutes.");
+        let unrelated = hashing.embed_one("Rust has no garbage collector.");
+        assert!(cosine_similarity(&query, &related) > cosine_similarity(&query, &unrelated));
+        assert!(
+            (cosine_similarity(&query, &hashing.embed_one("how DO i bake bread")) - 1.0).abs()
+                < 1e-6
+        );
+        assert_eq!(hashing.embed_one("!?"), vec![0.0; 256]);
+    }
+}
@@ -0,0 +1,152 @@
+//! HTTPのAPIでembeddingを生成する実装で共通の処理
+
+use futures::stream::{self, StreamExt, TryStreamExt};
+use futures::Future;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::time::Duration;
+
+/// # RequestSettings
+///
+/// HTTPのAPIでembeddingを生成する実装で共通のリクエストの設定
+///
+/// 入力を`batch_size`件ずつのリクエストに分けて最大`max_concurrency`個を並列に送り、
+/// 失敗したリクエストは`send_with_retry`で再試行する
+#[derive(Debug, Clone)]
+pub struct RequestSettings {
+    /// 1リクエストに含める入力の数
+    pub batch_size: usize,
+    /// 同時に送るリクエストの数
+    pub max_concurrency: usize,
+    pub max_retries: u32,
+    /// 1回目の再試行までの待ち時間
+    pub retry_delay: Duration,
+    /// タイムアウトなどを設定したclientに差し替えられる
+    pub client: reqwest::Client,
+}
+
+impl RequestSettings {
+    pub fn new(batch_size: usize, max_concurrency: usize, max_retries: u32) -> Self {
+        Self {
+            batch_size,
+            max_concurrency,
+            max_retries,
+            retry_delay: Duration::from_millis(500),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub(crate) async fn send(&self, request: RequestBuilder) -> anyhow::Result<Response> {
+        send_with_retry(request, self.max_retries, self.retry_delay).await
+    }
+
+    /// `texts`を`batch_size`件ずつ`embed_batch`に渡し、結果を元の順序でつなげる
+    pub(crate) async fn embed_in_batches<'a, F, Fut>(
+        &self,
+        texts: &'a [&'a str],
+        embed_batch: F,
+    ) -> anyhow::Result<Vec<Vec<f32>>>
+    where
+        F: FnMut(&'a [&'a str]) -> Fut,
+        Fut: Future<Output = anyhow::Result<Vec<Vec<f32>>>>,
+    {
+        let requests: Vec<_> = texts
+            .chunks(self.batch_size.max(1))
+            .map(embed_batch)
+            .collect();
+        buffered(requests, self.max_concurrency).await
+    }
+}
+
+/// 再試行までの待ち時間の上限
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// リクエストを送り、成功したレスポンスを返す
+///
+/// 429や5xxが返った場合と接続に失敗した場合は、`retry_delay`から倍々に待ち時間を伸ばしながら
+/// 最大`max_retries`回まで再試行する。待ち時間は`MAX_RETRY_DELAY`で頭打ちにする
+pub(crate) async fn send_with_retry(
+    request: RequestBuilder,
+    max_retries: u32,
+    retry_delay: Duration,
+) -> anyhow::Result<Response> {
+    let mut retries = 0;
+    loop {
+        let result = request
+            .try_clone()
+            .ok_or(anyhow::anyhow!("request body cannot be retried"))?
+            .send()
+            .await;
+        let error = match result {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) => {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+                let error = anyhow::anyhow!("embeddings API returned {}: {}", status, body);
+                if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+                    return Err(error);
+                }
+                error
+            }
+            Err(error) if error.is_connect() || error.is_timeout() => error.into(),
+            Err(error) => return Err(error.into()),
+        };
+        if retries >= max_retries {
+            return Err(error.context(format!("gave up after {} retries", retries)));
+        }
+        tokio::time::sleep(backoff(retry_delay, retries)).await;
+        retries += 1;
+    }
+}
+
+/// `retries`回目の再試行までの待ち時間。`max_retries`が大きくてもあふれない
+fn backoff(retry_delay: Duration, retries: u32) -> Duration {
+    retry_delay
+        .saturating_mul(2u32.saturating_pow(retries))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// バッチごとのリクエストを最大`max_concurrency`個ずつ並列に動かし、結果を元の順序でつなげる
+///
+/// futureは作っただけでは動かないので、bufferedで同時に動かす数を制限できる。
+/// bufferedは完了した順ではなく元の順序で結果を返す
+pub(crate) async fn buffered<F>(
+    requests: Vec<F>,
+    max_concurrency: usize,
+) -> anyhow::Result<Vec<Vec<f32>>>
+where
+    F: Future<Output = anyhow::Result<Vec<Vec<f32>>>>,
+{
+    let batches: Vec<Vec<Vec<f32>>> = stream::iter(requests)
+        .buffered(max_concurrency.max(1))
+        .try_collect()
+        .await?;
+    Ok(batches.into_iter().flatten().collect())
+}
+
+/// レスポンスのembeddingの数が入力の数と一致するか確かめる
+pub(crate) fn check_len(expected: usize, embeddings: &[Vec<f32>]) -> anyhow::Result<()> {
+    if embeddings.len() != expected {
+        anyhow::bail!("expected {} embeddings, got {}", expected, embeddings.len());
+    }
+    Ok(())
+}
+
+/// 保存したレスポンスのJSONをそのまま返すモックのレスポンス
+#[cfg(test)]
+pub(crate) fn fixture(body: &'static str) -> wiremock::ResponseTemplate {
+    wiremock::ResponseTemplate::new(200).set_body_raw(body, "application/json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let delay = Duration::from_millis(500);
+        assert_eq!(backoff(delay, 0), delay);
+        assert_eq!(backoff(delay, 3), Duration::from_secs(4));
+        assert_eq!(backoff(delay, 40), MAX_RETRY_DELAY);
+        assert_eq!(backoff(Duration::MAX, 1), MAX_RETRY_DELAY);
+    }
+}
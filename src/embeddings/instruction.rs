@@ -0,0 +1,185 @@
+use super::Embeddings;
+
+const PLACEHOLDER: &str = "{text}";
+
+/// # InstructedEmbeddings
+///
+/// queryとdocumentにそれぞれのテンプレートを適用してから`embeddings`に渡す。
+/// E5やBGEのように、queryとdocumentで異なる接頭辞を付ける前提で学習されたモデルで使う
+///
+/// テンプレートの`{text}`が入力に置き換わる
+///
+/// テンプレートを適用したembeddingは元のモデルのものとは混ぜられないので、`model_name`には
+/// `{元のモデル名}+instruct(query:{queryのテンプレート}|doc:{documentのテンプレート})`を返す
+///
+/// ```
+/// # use langchain::embeddings::{FakeEmbeddings, InstructedEmbeddings};
+/// let embeddings = InstructedEmbeddings::new(
+///     FakeEmbeddings::new(8),
+///     "query: {text}",
+///     "passage: {text}",
+/// )?;
+/// # anyhow::Ok(())
+/// ```
+#[derive(Clone)]
+pub struct InstructedEmbeddings<E: Embeddings> {
+    embeddings: E,
+    query_template: String,
+    document_template: String,
+    model_name: String,
+}
+
+impl<E: Embeddings> InstructedEmbeddings<E> {
+    /// テンプレートに`{text}`が無い場合はエラーになる
+    pub fn new(
+        embeddings: E,
+        query_template: impl Into<String>,
+        document_template: impl Into<String>,
+    ) -> anyhow::Result<Self> {
+        let query_template = query_template.into();
+        let document_template = document_template.into();
+        for template in [&query_template, &document_template] {
+            if !template.contains(PLACEHOLDER) {
+                anyhow::bail!("template must contain {}: {:?}", PLACEHOLDER, template);
+            }
+        }
+        let model_name = format!(
+            "{}+{}",
+            embeddings.model_name(),
+            instruction(&query_template, &document_template)
+        );
+        Ok(Self {
+            embeddings,
+            query_template,
+            document_template,
+            model_name,
+        })
+    }
+
+    /// E5(`intfloat/e5-*`)用。queryに`query: `、documentに`passage: `を付ける
+    pub fn e5(embeddings: E) -> Self {
+        Self::new(embeddings, "query: {text}", "passage: {text}").expect("templates contain {text}")
+    }
+
+    /// BGE(`BAAI/bge-*-en-v1.5`)用。queryにだけ検索用の指示を付ける
+    pub fn bge(embeddings: E) -> Self {
+        Self::new(
+            embeddings,
+            "Represent this sentence for searching relevant passages: {text}",
+            "{text}",
+        )
+        .expect("templates contain {text}")
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.embeddings
+    }
+}
+
+fn apply(template: &str, text: &str) -> String {
+    template.replace(PLACEHOLDER, text)
+}
+
+fn instruction(query_template: &str, document_template: &str) -> String {
+    format!(
+        "instruct(query:{}|doc:{})",
+        query_template, document_template
+    )
+}
+
+#[async_trait::async_trait]
+impl<E: Embeddings> Embeddings for InstructedEmbeddings<E> {
+    /// documentとして扱う
+    async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let texts: Vec<String> = texts
+            .iter()
+            .map(|text| apply(&self.document_template, text))
+            .collect();
+        let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+        self.embeddings.embed_texts(&texts).await
+    }
+
+    /// テンプレートを適用した上で、`embeddings`の`embed_query`に渡す
+    async fn embed_query(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        self.embeddings
+            .embed_query(&apply(&self.query_template, text))
+            .await
+    }
+
+    fn model_name(&self) -> &str {
+        &self.model_name
+    }
+
+    fn dimension(&self) -> Option<usize> {
+        self.embeddings.dimension()
+    }
+
+    fn identity(&self) -> String {
+        format!(
+            "{}+{}",
+            self.embeddings.identity(),
+            instruction(&self.query_template, &self.document_template)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::fake::RecordingEmbeddings;
+    use crate::embeddings::{EmbeddingsRouter, FakeEmbeddings, Routing};
+    use crate::schema::Document;
+    use crate::vectorstores::{InMemoryVectorStore, VectorStore};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_templates_with_vector_store() -> anyhow::Result<()> {
+        let embeddings = InstructedEmbeddings::e5(RecordingEmbeddings::default());
+        let store = InMemoryVectorStore::from_document(
+            vec![Document::new("apple", 0), Document::new("banana", 1)],
+            embeddings,
+        )
+        .await?;
+        store.similarity_search("fruit", Some(1), None).await?;
+        let embeddings = store.embeddings().unwrap();
+        assert_eq!(
+            embeddings.inner().texts(),
+            vec!["passage: apple", "passage: banana", "query: fruit"]
+        );
+        assert_eq!(
+            embeddings.model_name(),
+            "recording+instruct(query:query: {text}|doc:passage: {text})"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_not_mixed_with_uninstructed() -> anyhow::Result<()> {
+        let fake = FakeEmbeddings::new(4);
+        let instructed = InstructedEmbeddings::e5(fake);
+        assert_ne!(instructed.identity(), fake.identity());
+
+        let backends: Vec<Arc<dyn Embeddings>> = vec![Arc::new(instructed.clone()), Arc::new(fake)];
+        assert!(EmbeddingsRouter::new(backends, Routing::Priority).is_err());
+
+        // 保存したstoreはテンプレートを適用しないembeddingsでは読み込めない
+        let path =
+            std::env::temp_dir().join(format!("langchain-test-{}.json", uuid::Uuid::new_v4()));
+        let store =
+            InMemoryVectorStore::from_document(vec![Document::new("apple", 0)], instructed.clone())
+                .await?;
+        store.save(&path)?;
+        assert!(InMemoryVectorStore::load(&path, fake).is_err());
+        assert!(InMemoryVectorStore::load(&path, instructed).is_ok());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_template_without_placeholder() {
+        assert!(
+            InstructedEmbeddings::new(RecordingEmbeddings::default(), "query: ", "{text}").is_err()
+        );
+        assert_eq!(apply("Instruct: {text}", "hi"), "Instruct: hi");
+    }
+}
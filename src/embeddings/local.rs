@@ -0,0 +1,267 @@
+use super::Embeddings;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use std::path::Path;
+use std::sync::Arc;
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+
+/// # Pooling
+///
+/// トークンごとの出力から文のembeddingを作る方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pooling {
+    /// padding以外のトークンの平均。sentence-transformersのほとんどのモデルはこれ
+    #[default]
+    Mean,
+    /// 先頭の`[CLS]`トークンの出力
+    Cls,
+}
+
+/// # LocalEmbeddings
+///
+/// ローカルのディレクトリにあるsentence-transformerのモデル(BERT系)をCPUで動かしてembeddingを生成する。
+/// OpenAIのAPIに接続できない環境やCIで使う。`local` featureが必要
+///
+/// ディレクトリには`config.json`, `tokenizer.json`, `model.safetensors`が必要
+///
+/// ```no_run
+/// # use langchain::embeddings::LocalEmbeddings;
+/// let embeddings = LocalEmbeddings::load("./models/all-MiniLM-L6-v2")?;
+/// # anyhow::Ok(())
+/// ```
+#[derive(Clone)]
+pub struct LocalEmbeddings {
+    model: Arc<Model>,
+    model_name: String,
+    dimension: usize,
+    /// 1回の推論に含める入力の数
+    pub batch_size: usize,
+    pub pooling: Pooling,
+    /// ノルムを1にするかどうか
+    pub normalize: bool,
+}
+
+struct Model {
+    bert: BertModel,
+    tokenizer: Tokenizer,
+}
+
+impl LocalEmbeddings {
+    /// `model_name`はディレクトリ名になる
+    pub fn load(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        let config: Config =
+            serde_json::from_str(&std::fs::read_to_string(dir.join("config.json"))?)?;
+        let mut tokenizer =
+            Tokenizer::from_file(dir.join("tokenizer.json")).map_err(anyhow::Error::msg)?;
+        tokenizer
+            .with_padding(Some(PaddingParams::default()))
+            .with_truncation(Some(TruncationParams {
+                max_length: config.max_position_embeddings,
+                ..Default::default()
+            }))
+            .map_err(anyhow::Error::msg)?;
+        let device = Device::Cpu;
+        // SAFETY: モデルのファイルは読み込んでいる間に書き換えられないものとする
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(&[dir.join("model.safetensors")], DTYPE, &device)?
+        };
+        let bert = BertModel::load(vb, &config)?;
+        let model_name = dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(Self {
+            model: Arc::new(Model { bert, tokenizer }),
+            model_name,
+            dimension: config.hidden_size,
+            batch_size: 32,
+            pooling: Pooling::default(),
+            normalize: true,
+        })
+    }
+
+    async fn embed_inputs(&self, texts: Vec<String>) -> anyhow::Result<Vec<Vec<f32>>> {
+        let model = self.model.clone();
+        let batch_size = self.batch_size.max(1);
+        let pooling = self.pooling;
+        let normalize = self.normalize;
+        // 推論はCPUを使い続けるので、asyncのworkerを塞がないよう別のスレッドで動かす
+        tokio::task::spawn_blocking(move || {
+            let mut embeddings = Vec::with_capacity(texts.len());
+            for batch in texts.chunks(batch_size) {
+                embeddings.extend(model.embed_batch(batch, pooling, normalize)?);
+            }
+            Ok(embeddings)
+        })
+        .await?
+    }
+}
+
+impl Model {
+    fn embed_batch(
+        &self,
+        texts: &[String],
+        pooling: Pooling,
+        normalize: bool,
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts.to_vec(), true)
+            .map_err(anyhow::Error::msg)?;
+        let device = &self.bert.device;
+        let shape = (encodings.len(), encodings[0].len());
+        let ids: Vec<u32> = encodings
+            .iter()
+            .flat_map(|e| e.get_ids().to_vec())
+            .collect();
+        let mask: Vec<u32> = encodings
+            .iter()
+            .flat_map(|e| e.get_attention_mask().to_vec())
+            .collect();
+        let ids = Tensor::from_vec(ids, shape, device)?;
+        let mask = Tensor::from_vec(mask, shape, device)?;
+        let output = self.bert.forward(&ids, &ids.zeros_like()?, Some(&mask))?;
+
+        let pooled = match pooling {
+            Pooling::Mean => {
+                let mask = mask.to_dtype(DType::F32)?.unsqueeze(2)?;
+                let sum = output.broadcast_mul(&mask)?.sum(1)?;
+                sum.broadcast_div(&mask.sum(1)?)?
+            }
+            Pooling::Cls => output.narrow(1, 0, 1)?.squeeze(1)?,
+        };
+        let pooled = if normalize {
+            pooled.broadcast_div(&pooled.sqr()?.sum_keepdim(1)?.sqrt()?)?
+        } else {
+            pooled
+        };
+        Ok(pooled.to_vec2()?)
+    }
+}
+
+#[async_trait::async_trait]
+impl Embeddings for LocalEmbeddings {
+    async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.embed_inputs(texts.iter().map(|text| text.to_string()).collect())
+            .await
+    }
+
+    fn model_name(&self) -> &str {
+        &self.model_name
+    }
+
+    fn dimension(&self) -> Option<usize> {
+        Some(self.dimension)
+    }
+
+    fn identity(&self) -> String {
+        format!(
+            "{}(pooling={:?},normalize={})",
+            self.model_name, self.pooling, self.normalize
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Document;
+    use crate::vectorstores::utils;
+    use candle_nn::VarMap;
+
+    /// 乱数で初期化した小さいBERTのモデルをディレクトリに書き出す
+    fn write_tiny_model(dir: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let words = ["[PAD]", "[UNK]", "apple", "banana", "cherry", "durian"];
+        let config = serde_json::json!({
+            "vocab_size": words.len(),
+            "hidden_size": 8,
+            "num_hidden_layers": 1,
+            "num_attention_heads": 2,
+            "intermediate_size": 16,
+            "hidden_act": "gelu",
+            "hidden_dropout_prob": 0.0,
+            "max_position_embeddings": 16,
+            "type_vocab_size": 1,
+            "initializer_range": 0.02,
+            "layer_norm_eps": 1e-12,
+            "pad_token_id": 0,
+            "classifier_dropout": null,
+            "model_type": null,
+        });
+        std::fs::write(dir.join("config.json"), config.to_string())?;
+
+        let vocab: serde_json::Map<String, serde_json::Value> = words
+            .iter()
+            .enumerate()
+            .map(|(i, word)| (word.to_string(), i.into()))
+            .collect();
+        let tokenizer = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": { "type": "Whitespace" },
+            "post_processor": null,
+            "decoder": null,
+            "model": { "type": "WordLevel", "vocab": vocab, "unk_token": "[UNK]" },
+        });
+        std::fs::write(dir.join("tokenizer.json"), tokenizer.to_string())?;
+
+        let varmap = VarMap::new();
+        let config: Config = serde_json::from_value(config)?;
+        BertModel::load(
+            VarBuilder::from_varmap(&varmap, DTYPE, &Device::Cpu),
+            &config,
+        )?;
+        varmap.save(dir.join("model.safetensors"))?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_local_embeddings() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("langchain-test-{}", uuid::Uuid::new_v4()));
+        write_tiny_model(&dir)?;
+        let mut embeddings = LocalEmbeddings::load(&dir)?;
+        assert_eq!(embeddings.dimension(), Some(8));
+
+        let documents = vec![
+            Document::new("apple", 0),
+            Document::new("banana cherry durian apple", 1),
+            Document::new("cherry", 2),
+        ];
+        let batched = embeddings.embed_document(&documents).await?;
+        assert_eq!(batched.len(), 3);
+        for embedding in &batched {
+            assert_eq!(embedding.len(), 8);
+            assert!((utils::norm(embedding) - 1.0).abs() < 1e-4);
+        }
+
+        // paddingの有無で結果が変わらない
+        embeddings.batch_size = 1;
+        let single = embeddings.embed_document(&documents).await?;
+        for (a, b) in batched.iter().zip(&single) {
+            assert!(a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-4));
+        }
+        let query = embeddings.embed_query("apple").await?;
+        assert!(query
+            .iter()
+            .zip(&single[0])
+            .all(|(a, b)| (a - b).abs() < 1e-4));
+
+        embeddings.normalize = false;
+        embeddings.pooling = Pooling::Cls;
+        let cls = embeddings.embed_query("apple banana").await?;
+        assert_eq!(cls.len(), 8);
+        assert!((utils::norm(&cls) - 1.0).abs() > 1e-3);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -1,11 +1,107 @@
+pub mod cache;
+pub mod cohere;
+pub mod fake;
+pub mod instruction;
+#[cfg(feature = "local")]
+pub mod local;
+pub mod ollama;
 pub mod openai;
+pub mod router;
+pub mod tei;
+pub mod token_limit;
 
+mod http;
+
+pub use cache::*;
+pub use cohere::*;
+pub use fake::*;
+pub use http::RequestSettings;
+pub use instruction::InstructedEmbeddings;
+#[cfg(feature = "local")]
+pub use local::*;
+pub use ollama::*;
 pub use openai::*;
+pub use router::*;
+pub use tei::*;
+pub use token_limit::TokenLimitPolicy;
 
 use crate::schema::Document;
 
+/// # Embeddings
+///
+/// テキストをベクトルにするもの。実装するのは`embed_texts`だけでよい
+///
+/// `dyn Embeddings`として使えるように、ジェネリクスを使うメソッドは`Self: Sized`にしている
 #[async_trait::async_trait]
 pub trait Embeddings: Send + Sync + 'static {
-    async fn embed_document(&self, documents: &Vec<Document>) -> anyhow::Result<Vec<Vec<f32>>>;
-    async fn embed_query(&self, text: &str) -> anyhow::Result<Vec<f32>>;
+    /// `texts`と同じ順序でembeddingを返す
+    async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>>;
+
+    /// `String`などのスライスをそのまま渡せる`embed_texts`
+    async fn embed<T: AsRef<str> + Sync>(&self, texts: &[T]) -> anyhow::Result<Vec<Vec<f32>>>
+    where
+        Self: Sized,
+    {
+        let texts: Vec<&str> = texts.iter().map(AsRef::as_ref).collect();
+        self.embed_texts(&texts).await
+    }
+
+    /// 各documentの`page_content`のembedding
+    async fn embed_document(&self, documents: &[Document]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let texts: Vec<&str> = documents
+            .iter()
+            .map(|doc| doc.page_content.as_str())
+            .collect();
+        self.embed_texts(&texts).await
+    }
+
+    /// 検索に使うqueryのembedding。documentと違う方法でembeddingを作るモデルでは上書きする
+    async fn embed_query(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        self.embed_texts(&[text])
+            .await?
+            .pop()
+            .ok_or(anyhow::anyhow!("no embedding was returned for the query"))
+    }
+
+    /// embeddingを生成するモデルの名前
+    ///
+    /// モデルが違うembedding同士は比較できないので、保存したVectorStoreを読み込む際の確認に使う
+    fn model_name(&self) -> &str;
+    /// 出力されるベクトルの次元数。事前にわからない場合はNone
+    fn dimension(&self) -> Option<usize> {
+        None
+    }
+
+    /// 同じテキストに同じembeddingを返すものを区別する識別子。`CachedEmbeddings`のkeyに含める
+    ///
+    /// デフォルトは`model_name`と`dimension`をつなげたもの。
+    /// 同じモデルでも設定によってdocumentのembeddingが変わる実装は、その設定も含めるように上書きする
+    fn identity(&self) -> String {
+        match self.dimension() {
+            Some(dimension) => format!("{}:{}", self.model_name(), dimension),
+            None => self.model_name().to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_default_methods() -> anyhow::Result<()> {
+        let fake = FakeEmbeddings::new(4);
+        let texts = vec!["a".to_string(), "b".to_string()];
+        let expected = fake.embed_texts(&["a", "b"]).await?;
+        assert_eq!(fake.embed(&texts).await?, expected);
+        let documents = vec![Document::new("a", 0), Document::new("b", 1)];
+        assert_eq!(fake.embed_document(&documents).await?, expected);
+
+        let embeddings: Arc<dyn Embeddings> = Arc::new(fake);
+        assert_eq!(embeddings.embed_query("b").await?, expected[1]);
+        assert_eq!(embeddings.dimension(), Some(4));
+        assert_eq!(HashingEmbeddings::new(8).identity(), "hashing:8");
+        Ok(())
+    }
 }
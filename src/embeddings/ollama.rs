@@ -0,0 +1,106 @@
+use super::http::{self, RequestSettings};
+use super::Embeddings;
+
+/// # OllamaEmbedding
+///
+/// Ollamaの`/api/embed`でembeddingを生成する
+///
+/// Ollamaのembedding APIにはqueryとdocumentを区別する指定がないので、どちらも同じように扱う
+#[derive(Debug, Clone)]
+pub struct OllamaEmbedding {
+    pub model: String,
+    /// デフォルトは`http://localhost:11434`
+    pub api_base: String,
+    /// 最大長を超える入力を切り詰めるかどうか。`false`の場合はエラーになる
+    pub truncate: bool,
+    pub request: RequestSettings,
+}
+
+impl OllamaEmbedding {
+    pub fn new(model: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            api_base: "http://localhost:11434".to_string(),
+            truncate: true,
+            request: RequestSettings::new(32, 1, 3),
+        }
+    }
+
+    async fn embed_batch(&self, input: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let request = self
+            .request
+            .client
+            .post(format!("{}/api/embed", self.api_base.trim_end_matches('/')))
+            .json(&EmbedRequest {
+                model: &self.model,
+                input,
+                truncate: self.truncate,
+            });
+        let response = self.request.send(request).await?;
+        let embeddings = response.json::<EmbedResponse>().await?.embeddings;
+        http::check_len(input.len(), &embeddings)?;
+        Ok(embeddings)
+    }
+}
+
+#[derive(serde::Serialize)]
+struct EmbedRequest<'a> {
+    model: &'a str,
+    input: &'a [&'a str],
+    truncate: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct EmbedResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+#[async_trait::async_trait]
+impl Embeddings for OllamaEmbedding {
+    async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.request
+            .embed_in_batches(texts, |batch| self.embed_batch(batch))
+            .await
+    }
+
+    fn model_name(&self) -> &str {
+        &self.model
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::http::fixture;
+    use wiremock::matchers::{body_json, method, path};
+    use wiremock::{Mock, MockServer};
+
+    #[tokio::test]
+    async fn test_embed_with_fixture() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/embed"))
+            .and(body_json(serde_json::json!({
+                "model": "nomic-embed-text",
+                "input": ["The sky is blue", "Grass is green"],
+                "truncate": true,
+            })))
+            .respond_with(fixture(include_str!(
+                "../../tests/fixtures/embeddings/ollama_embed.json"
+            )))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let embedding = OllamaEmbedding {
+            api_base: server.uri(),
+            ..OllamaEmbedding::new("nomic-embed-text")
+        };
+        let result = embedding
+            .embed_texts(&["The sky is blue", "Grass is green"])
+            .await?;
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0], vec![0.0123, -0.0456, 0.0789, 0.0311]);
+        assert_eq!(result[1], vec![-0.0201, 0.0372, -0.0155, 0.0604]);
+        Ok(())
+    }
+}
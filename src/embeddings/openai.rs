@@ -1,53 +1,606 @@
+use super::http;
+use super::token_limit::{self, TokenLimitPolicy};
 use super::Embeddings;
-use crate::schema::Document;
-use async_openai::{config::OpenAIConfig, types::CreateEmbeddingRequestArgs, Client};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::time::Duration;
 
+const DEFAULT_API_BASE: &str = "https://api.openai.com/v1";
+
+/// # OpenAIEmbedding
+///
+/// OpenAIのembeddings APIでembeddingを生成する
+///
+/// `embed_texts`は入力を`batch_size`件以下かつ合計`max_batch_tokens`トークン以下のリクエストに分け、
+/// 最大`max_concurrency`個を並列に送る。
+/// 結果は常に入力と同じ順序になる
+///
+/// 失敗したリクエストは最大`max_retries`回まで再試行する。条件と待ち時間は他のHTTPのembeddingと共通
+/// (`http::send_with_retry`)
+///
+/// `max_tokens`を超える入力は送る前に`token_limit_policy`に従って切り詰めるか分割する
+///
+/// 接続先やAPIキーなどを変える場合は`OpenAIEmbedding::builder`を使う
 #[derive(Debug, Clone)]
 pub struct OpenAIEmbedding {
     pub model: String,
-    pub client: Client<OpenAIConfig>,
+    /// 出力するベクトルの次元数。`text-embedding-3-*`でのみ指定できる
+    pub dimensions: Option<usize>,
+    /// 1リクエストに含めるdocumentの数
+    pub batch_size: usize,
+    /// 1リクエストに含める入力の合計トークン数(cl100k_base)の上限。APIの上限は300,000
+    pub max_batch_tokens: usize,
+    /// 同時に送るリクエストの数
+    pub max_concurrency: usize,
+    pub max_retries: u32,
+    /// 1回目の再試行までの待ち時間
+    pub retry_delay: Duration,
+    /// 1つの入力の最大トークン数(cl100k_base)
+    pub max_tokens: usize,
+    pub token_limit_policy: TokenLimitPolicy,
+    api_base: String,
+    api_key: String,
+    /// Azure OpenAIの場合のみ
+    api_version: Option<String>,
+    client: reqwest::Client,
 }
 
 impl<'a> OpenAIEmbedding {
     pub fn new(model: &'a str) -> Self {
         Self {
             model: model.to_string(),
-            client: Client::new(),
+            ..Default::default()
         }
     }
+
+    pub fn builder() -> OpenAIEmbeddingBuilder {
+        OpenAIEmbeddingBuilder::default()
+    }
 }
 
 impl Default for OpenAIEmbedding {
     fn default() -> Self {
         Self {
             model: "text-embedding-ada-002".to_string(),
-            client: Client::new(),
+            dimensions: None,
+            api_base: DEFAULT_API_BASE.to_string(),
+            api_key: std::env::var("OPENAI_API_KEY").unwrap_or_default(),
+            batch_size: 512,
+            max_batch_tokens: 300_000,
+            max_concurrency: 4,
+            max_retries: 6,
+            retry_delay: Duration::from_millis(500),
+            max_tokens: 8191,
+            token_limit_policy: TokenLimitPolicy::default(),
+            api_version: None,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+/// # OpenAIEmbeddingBuilder
+///
+/// OpenAI互換のAPIであれば、`api_base`を変えることでプロキシやローカルのサーバーにも接続できる
+///
+/// ```no_run
+/// # use langchain::embeddings::OpenAIEmbedding;
+/// # use std::time::Duration;
+/// let embedding = OpenAIEmbedding::builder()
+///     .model("text-embedding-3-large")
+///     .dimensions(1024)
+///     .api_base("http://localhost:8080/v1")
+///     .api_key("sk-...")
+///     .timeout(Duration::from_secs(30))
+///     .build()?;
+/// # anyhow::Ok(())
+/// ```
+///
+/// Azure OpenAIの場合は`azure`でendpointとdeploymentを指定する
+///
+/// ```no_run
+/// # use langchain::embeddings::OpenAIEmbedding;
+/// let embedding = OpenAIEmbedding::builder()
+///     .azure("https://my-resource.openai.azure.com", "my-embedding", "2024-02-01")
+///     .build()?;
+/// # anyhow::Ok(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct OpenAIEmbeddingBuilder {
+    model: Option<String>,
+    dimensions: Option<usize>,
+    api_base: Option<String>,
+    api_key: Option<String>,
+    organization: Option<String>,
+    headers: Vec<(String, String)>,
+    timeout: Option<Duration>,
+    api_version: Option<String>,
+}
+
+impl OpenAIEmbeddingBuilder {
+    /// デフォルトは`text-embedding-ada-002`
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    /// 出力するベクトルの次元数。`text-embedding-3-*`でのみ指定できる
+    pub fn dimensions(mut self, dimensions: usize) -> Self {
+        self.dimensions = Some(dimensions);
+        self
+    }
+
+    /// デフォルトは`https://api.openai.com/v1`
+    pub fn api_base(mut self, api_base: impl Into<String>) -> Self {
+        self.api_base = Some(api_base.into());
+        self
+    }
+
+    /// デフォルトは環境変数`OPENAI_API_KEY`の値。空の場合は`Authorization`ヘッダーを送らない
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// `OpenAI-Organization`ヘッダーで送る
+    pub fn organization(mut self, organization: impl Into<String>) -> Self {
+        self.organization = Some(organization.into());
+        self
+    }
+
+    /// すべてのリクエストに付けるヘッダーを追加する
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// 1回のリクエストのタイムアウト。タイムアウトした場合も再試行する
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Azure OpenAIの`deployment`に接続する
+    ///
+    /// APIキーは`Authorization`ではなく`api-key`ヘッダーで送り、デフォルトは環境変数`AZURE_OPENAI_API_KEY`の値。
+    /// `model`を指定しない場合はdeployment名をモデルの名前とする
+    pub fn azure(
+        mut self,
+        endpoint: impl Into<String>,
+        deployment: impl Into<String>,
+        api_version: impl Into<String>,
+    ) -> Self {
+        let deployment = deployment.into();
+        self.api_base = Some(format!(
+            "{}/openai/deployments/{}",
+            endpoint.into().trim_end_matches('/'),
+            deployment
+        ));
+        self.model = self.model.or(Some(deployment));
+        self.api_version = Some(api_version.into());
+        self
+    }
+
+    /// ヘッダーの名前や値が不正な場合はエラーになる
+    pub fn build(self) -> anyhow::Result<OpenAIEmbedding> {
+        let mut headers = HeaderMap::new();
+        if let Some(organization) = self.organization {
+            headers.insert("OpenAI-Organization", HeaderValue::from_str(&organization)?);
+        }
+        for (name, value) in self.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(&value)?,
+            );
+        }
+        let mut client = reqwest::Client::builder().default_headers(headers);
+        if let Some(timeout) = self.timeout {
+            client = client.timeout(timeout);
         }
+        let default = OpenAIEmbedding::default();
+        Ok(OpenAIEmbedding {
+            model: self.model.unwrap_or(default.model),
+            dimensions: self.dimensions,
+            api_base: self
+                .api_base
+                .map(|api_base| api_base.trim_end_matches('/').to_string())
+                .unwrap_or(default.api_base),
+            api_key: match (self.api_key, &self.api_version) {
+                (Some(api_key), _) => api_key,
+                (None, Some(_)) => std::env::var("AZURE_OPENAI_API_KEY").unwrap_or_default(),
+                (None, None) => default.api_key,
+            },
+            api_version: self.api_version,
+            client: client.build()?,
+            ..default
+        })
+    }
+}
+
+#[derive(serde::Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dimensions: Option<usize>,
+}
+
+#[derive(serde::Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(serde::Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl OpenAIEmbedding {
+    /// `texts`のembeddingを`texts`と同じ順序で返す
+    async fn embed_inputs(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let (input, weights) =
+            token_limit::split_inputs(texts, self.max_tokens, self.token_limit_policy)?;
+        let requests: Vec<_> =
+            token_limit::batches(&weights, self.batch_size, self.max_batch_tokens)
+                .into_iter()
+                .map(|batch| self.embed_batch(&input[batch]))
+                .collect();
+        let embeddings = http::buffered(requests, self.max_concurrency).await?;
+        Ok(token_limit::combine(embeddings, &weights))
+    }
+
+    /// 1回のリクエストで`input`のembeddingを生成する。必要なら再試行する
+    async fn embed_batch(&self, input: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let mut request = self
+            .client
+            .post(format!("{}/embeddings", self.api_base))
+            .json(&EmbeddingRequest {
+                model: &self.model,
+                input,
+                dimensions: self.dimensions,
+            });
+        if let Some(api_version) = &self.api_version {
+            request = request.query(&[("api-version", api_version)]);
+            if !self.api_key.is_empty() {
+                request = request.header("api-key", &self.api_key);
+            }
+        } else if !self.api_key.is_empty() {
+            request = request.bearer_auth(&self.api_key);
+        }
+        let response = http::send_with_retry(request, self.max_retries, self.retry_delay).await?;
+        let mut data = response.json::<EmbeddingResponse>().await?.data;
+        // レスポンスの順序は保証されていないのでindexで並べ直す
+        data.sort_by_key(|d| d.index);
+        let embeddings: Vec<Vec<f32>> = data.into_iter().map(|d| d.embedding).collect();
+        http::check_len(input.len(), &embeddings)?;
+        Ok(embeddings)
     }
 }
 
 #[async_trait::async_trait]
 impl Embeddings for OpenAIEmbedding {
-    async fn embed_document(&self, documents: &Vec<Document>) -> anyhow::Result<Vec<Vec<f32>>> {
-        let request = CreateEmbeddingRequestArgs::default()
-            .model(&self.model)
-            .input(
-                documents
-                    .iter()
-                    .map(|doc| doc.page_content.clone())
-                    .collect::<Vec<_>>(),
-            )
+    async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.embed_inputs(texts).await
+    }
+
+    fn model_name(&self) -> &str {
+        &self.model
+    }
+
+    fn dimension(&self) -> Option<usize> {
+        if self.dimensions.is_some() {
+            return self.dimensions;
+        }
+        match self.model.as_str() {
+            "text-embedding-ada-002" | "text-embedding-3-small" => Some(1536),
+            "text-embedding-3-large" => Some(3072),
+            _ => None,
+        }
+    }
+
+    /// 長い入力の扱いによってもembeddingが変わるので、`max_tokens`と`token_limit_policy`も含める
+    fn identity(&self) -> String {
+        format!(
+            "{}(dimensions={:?},max_tokens={},token_limit_policy={:?})",
+            self.model, self.dimensions, self.max_tokens, self.token_limit_policy
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Document;
+    use std::time::Instant;
+    use wiremock::matchers::{body_partial_json, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    /// 入力の文字数をembeddingにして、dataを逆順に返すモック
+    fn reversed_lengths(request: &Request) -> ResponseTemplate {
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        let data: Vec<serde_json::Value> = body["input"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(index, input)| {
+                let length = input.as_str().unwrap().len() as f32;
+                serde_json::json!({ "object": "embedding", "index": index, "embedding": [length] })
+            })
+            .collect();
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({ "data": data }))
+    }
+
+    fn embedding(server: &MockServer) -> OpenAIEmbedding {
+        let embedding = OpenAIEmbedding::builder()
+            .model("text-embedding-3-small")
+            .api_base(server.uri())
+            .api_key("test-key")
+            .build()
+            .unwrap();
+        OpenAIEmbedding {
+            retry_delay: Duration::from_millis(10),
+            ..embedding
+        }
+    }
+
+    fn documents(n: usize) -> Vec<Document> {
+        (0..n)
+            .map(|i| Document::new(&"a".repeat(i + 1), i))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_embed_document_in_batches() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/embeddings"))
+            .and(header("authorization", "Bearer test-key"))
+            .respond_with(reversed_lengths)
+            .expect(3)
+            .mount(&server)
+            .await;
+        let embedding = OpenAIEmbedding {
+            batch_size: 4,
+            ..embedding(&server)
+        };
+
+        let result = embedding.embed_document(&documents(10)).await?;
+        let expected: Vec<Vec<f32>> = (1..=10).map(|i| vec![i as f32]).collect();
+        assert_eq!(result, expected);
+        assert!(embedding.embed_texts(&[]).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_embed_document_limits_tokens_per_request() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(reversed_lengths)
+            .expect(3)
+            .mount(&server)
+            .await;
+        let embedding = OpenAIEmbedding {
+            max_batch_tokens: 4,
+            ..embedding(&server)
+        };
+
+        // "hello world"は2トークンなので、1リクエストに2件ずつになる
+        let result = embedding.embed_texts(&["hello world"; 6]).await?;
+        assert_eq!(result, vec![vec![11.0]; 6]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_embed_document_limits_concurrency() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(move |request: &Request| {
+                reversed_lengths(request).set_delay(Duration::from_millis(100))
+            })
+            .expect(4)
+            .mount(&server)
+            .await;
+        let embedding = OpenAIEmbedding {
+            batch_size: 1,
+            max_concurrency: 2,
+            ..embedding(&server)
+        };
+
+        // 2つずつしか並列に送らないので、少なくとも2回分は待つことになる
+        let start = Instant::now();
+        let result = embedding.embed_document(&documents(4)).await?;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(result, vec![vec![1.0], vec![2.0], vec![3.0], vec![4.0]]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retry_on_rate_limit_and_server_error() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(reversed_lengths)
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let result = embedding(&server).embed_query("abc").await?;
+        assert_eq!(result, vec![3.0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_on_client_error() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_string("bad request"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let error = embedding(&server).embed_query("abc").await.unwrap_err();
+        assert!(error.to_string().contains("400"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_give_up_after_max_retries() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&server)
+            .await;
+        let embedding = OpenAIEmbedding {
+            max_retries: 2,
+            ..embedding(&server)
+        };
+        assert!(embedding.embed_query("abc").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_token_limit_policy() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(reversed_lengths)
+            .expect(2)
+            .mount(&server)
+            .await;
+        let long = Document::new(&["hello world"; 10].join(" "), 0);
+        let documents = vec![Document::new("short", 1), long];
+
+        // 1つの入力が長すぎても、他の入力と一緒に処理できる
+        let embedding = OpenAIEmbedding {
+            max_tokens: 8,
+            token_limit_policy: TokenLimitPolicy::Truncate,
+            ..embedding(&server)
+        };
+        let result = embedding.embed_document(&documents).await?;
+        assert_eq!(
+            result,
+            vec![vec![5.0], vec![["hello world"; 4].join(" ").len() as f32]]
+        );
+
+        // 分割した場合は平均をノルム1にしたものになる
+        let embedding = OpenAIEmbedding {
+            token_limit_policy: TokenLimitPolicy::SplitAndAverage,
+            ..embedding
+        };
+        let result = embedding.embed_document(&documents).await?;
+        assert_eq!(result, vec![vec![5.0], vec![1.0]]);
+
+        // エラーにする場合はリクエストを送らない
+        let embedding = OpenAIEmbedding {
+            token_limit_policy: TokenLimitPolicy::Error,
+            ..embedding
+        };
+        assert!(embedding.embed_document(&documents).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_builder() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/embeddings"))
+            .and(header("authorization", "Bearer sk-test"))
+            .and(header("openai-organization", "org-test"))
+            .and(header("x-proxy-token", "secret"))
+            .and(body_partial_json(serde_json::json!({
+                "model": "text-embedding-3-large",
+                "dimensions": 256,
+            })))
+            .respond_with(reversed_lengths)
+            .expect(1)
+            .mount(&server)
+            .await;
+        let embedding = OpenAIEmbedding::builder()
+            .model("text-embedding-3-large")
+            .dimensions(256)
+            .api_base(format!("{}/v1/", server.uri()))
+            .api_key("sk-test")
+            .organization("org-test")
+            .header("X-Proxy-Token", "secret")
+            .build()?;
+        assert_eq!(embedding.dimension(), Some(256));
+        assert_eq!(embedding.embed_query("abc").await?, vec![3.0]);
+
+        assert!(OpenAIEmbedding::builder()
+            .header("X-Invalid", "line\nbreak")
+            .build()
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timeout() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(move |request: &Request| {
+                reversed_lengths(request).set_delay(Duration::from_millis(500))
+            })
+            .expect(2)
+            .mount(&server)
+            .await;
+        let embedding = OpenAIEmbedding::builder()
+            .api_base(server.uri())
+            .timeout(Duration::from_millis(50))
             .build()?;
-        let response = self.client.embeddings().create(request).await?;
-        Ok(response.data.iter().map(|d| d.embedding.clone()).collect())
+        let embedding = OpenAIEmbedding {
+            max_retries: 1,
+            retry_delay: Duration::from_millis(10),
+            ..embedding
+        };
+        assert!(embedding.embed_query("abc").await.is_err());
+        Ok(())
     }
 
-    async fn embed_query(&self, text: &str) -> anyhow::Result<Vec<f32>> {
-        let request = CreateEmbeddingRequestArgs::default()
-            .model(&self.model)
-            .input(vec![text.to_string()])
+    #[tokio::test]
+    async fn test_azure() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/openai/deployments/my-embedding/embeddings"))
+            .and(query_param("api-version", "2024-02-01"))
+            .and(header("api-key", "azure-key"))
+            .and(body_partial_json(
+                serde_json::json!({ "input": ["first", "second"] }),
+            ))
+            .respond_with(http::fixture(include_str!(
+                "../../tests/fixtures/embeddings/azure_openai_embeddings.json"
+            )))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let embedding = OpenAIEmbedding::builder()
+            .azure(format!("{}/", server.uri()), "my-embedding", "2024-02-01")
+            .api_key("azure-key")
             .build()?;
-        let response = self.client.embeddings().create(request).await?;
-        Ok(response.data[0].embedding.clone())
+        assert_eq!(embedding.model_name(), "my-embedding");
+        let result = embedding.embed_texts(&["first", "second"]).await?;
+        assert_eq!(
+            result,
+            vec![
+                vec![-0.0149, 0.0333, 0.0218, -0.0402],
+                vec![0.0381, 0.0127, -0.0264, 0.0459],
+            ]
+        );
+        let requests = server.received_requests().await.unwrap();
+        assert!(requests[0]
+            .headers
+            .iter()
+            .all(|(name, _)| name.as_str() != "authorization"));
+        Ok(())
     }
 }
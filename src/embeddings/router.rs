@@ -0,0 +1,333 @@
+use super::Embeddings;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// # Routing
+///
+/// `EmbeddingsRouter`がどのbackendから使うか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Routing {
+    /// 常に先頭のbackendから順に試す
+    #[default]
+    Priority,
+    /// 呼び出しごとに最初に試すbackendをずらして負荷を分散する
+    RoundRobin,
+}
+
+/// # EmbeddingsRouter
+///
+/// 同じモデルのembeddingを返す複数のbackend(例えばOpenAIとAzure OpenAI)に振り分け、
+/// 失敗した場合は次のbackendで再試行する
+///
+/// 別のベクトルが1つのstoreに混ざらないよう、すべてのbackendの`identity`が一致している必要がある。
+/// また、返ってきたベクトルの次元数が`dimension`と違う場合は失敗として扱う
+///
+/// 連続して`failure_threshold`回失敗したbackendは`cooldown`の間は使わない(circuit breaker)。
+/// すべてのbackendが使えない状態の場合は、諦めずにすべてを順に試す
+///
+/// cloneしたものはbackendの状態を共有する
+#[derive(Clone)]
+pub struct EmbeddingsRouter {
+    backends: Vec<Backend>,
+    routing: Routing,
+    next: Arc<AtomicUsize>,
+    model_name: String,
+    dimension: Option<usize>,
+    identity: String,
+    pub failure_threshold: u32,
+    pub cooldown: Duration,
+}
+
+#[derive(Clone)]
+struct Backend {
+    embeddings: Arc<dyn Embeddings>,
+    circuit: Arc<Mutex<Circuit>>,
+}
+
+#[derive(Default)]
+struct Circuit {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl EmbeddingsRouter {
+    /// backendが無い場合と、`identity`が一致しない場合はエラーになる
+    pub fn new(backends: Vec<Arc<dyn Embeddings>>, routing: Routing) -> anyhow::Result<Self> {
+        let first = backends
+            .first()
+            .ok_or(anyhow::anyhow!("at least one backend is required"))?;
+        let model_name = first.model_name().to_string();
+        let dimension = first.dimension();
+        let identity = first.identity();
+        for (i, backend) in backends.iter().enumerate() {
+            if backend.identity() != identity {
+                anyhow::bail!(
+                    "backend {} ({}) does not match backend 0 ({})",
+                    i,
+                    backend.identity(),
+                    identity
+                );
+            }
+        }
+        Ok(Self {
+            backends: backends
+                .into_iter()
+                .map(|embeddings| Backend {
+                    embeddings,
+                    circuit: Arc::new(Mutex::new(Circuit::default())),
+                })
+                .collect(),
+            routing,
+            next: Arc::new(AtomicUsize::new(0)),
+            model_name,
+            dimension,
+            identity,
+            failure_threshold: 3,
+            cooldown: Duration::from_secs(30),
+        })
+    }
+
+    /// 今回試すbackendの順序
+    fn order(&self) -> Vec<&Backend> {
+        let len = self.backends.len();
+        let start = match self.routing {
+            Routing::Priority => 0,
+            Routing::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % len,
+        };
+        let ordered = (0..len).map(|i| &self.backends[(start + i) % len]);
+        let now = Instant::now();
+        let available: Vec<&Backend> = ordered
+            .clone()
+            .filter(|backend| !matches!(backend.circuit().open_until, Some(until) if until > now))
+            .collect();
+        if available.is_empty() {
+            ordered.collect()
+        } else {
+            available
+        }
+    }
+
+    fn check_dimension(&self, embeddings: &[Vec<f32>]) -> anyhow::Result<()> {
+        if let Some(dimension) = self.dimension {
+            if let Some(embedding) = embeddings.iter().find(|e| e.len() != dimension) {
+                anyhow::bail!(
+                    "expected embeddings of dimension {}, got {}",
+                    dimension,
+                    embedding.len()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn route<'a, F, Fut>(&'a self, call: F) -> anyhow::Result<Vec<Vec<f32>>>
+    where
+        F: Fn(&'a dyn Embeddings) -> Fut,
+        Fut: std::future::Future<Output = anyhow::Result<Vec<Vec<f32>>>>,
+    {
+        let mut errors = Vec::new();
+        for backend in self.order() {
+            let result = call(backend.embeddings.as_ref())
+                .await
+                .and_then(|embeddings| self.check_dimension(&embeddings).map(|_| embeddings));
+            match result {
+                Ok(embeddings) => {
+                    *backend.circuit() = Circuit::default();
+                    return Ok(embeddings);
+                }
+                Err(error) => {
+                    let mut circuit = backend.circuit();
+                    circuit.consecutive_failures += 1;
+                    if circuit.consecutive_failures >= self.failure_threshold {
+                        circuit.open_until = Some(Instant::now() + self.cooldown);
+                    }
+                    errors.push(error);
+                }
+            }
+        }
+        let count = errors.len();
+        Err(errors
+            .pop()
+            .unwrap_or(anyhow::anyhow!("no backend was tried"))
+            .context(format!("all {} backends failed", count)))
+    }
+}
+
+impl Backend {
+    fn circuit(&self) -> std::sync::MutexGuard<'_, Circuit> {
+        self.circuit.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[async_trait::async_trait]
+impl Embeddings for EmbeddingsRouter {
+    async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.route(|backend| backend.embed_texts(texts)).await
+    }
+
+    async fn embed_query(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        let mut embeddings = self
+            .route(|backend| async move { Ok(vec![backend.embed_query(text).await?]) })
+            .await?;
+        Ok(embeddings.remove(0))
+    }
+
+    fn model_name(&self) -> &str {
+        &self.model_name
+    }
+
+    fn dimension(&self) -> Option<usize> {
+        self.dimension
+    }
+
+    fn identity(&self) -> String {
+        self.identity.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::{FakeEmbeddings, OpenAIEmbedding, TokenLimitPolicy};
+    use std::sync::atomic::AtomicBool;
+
+    /// `failing`の間は失敗し、呼ばれた回数を数えるbackend
+    #[derive(Default)]
+    struct Flaky {
+        name: &'static str,
+        dimension: usize,
+        failing: AtomicBool,
+        calls: AtomicUsize,
+    }
+
+    impl Flaky {
+        fn new(name: &'static str, dimension: usize) -> Arc<Self> {
+            Arc::new(Self {
+                name,
+                dimension,
+                ..Default::default()
+            })
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Embeddings for Flaky {
+        async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.failing.load(Ordering::SeqCst) {
+                anyhow::bail!("rate limited");
+            }
+            Ok(texts
+                .iter()
+                .map(|_| vec![self.calls() as f32; self.dimension])
+                .collect())
+        }
+
+        fn model_name(&self) -> &str {
+            self.name
+        }
+
+        fn dimension(&self) -> Option<usize> {
+            Some(self.dimension)
+        }
+    }
+
+    #[test]
+    fn test_rejects_different_models() {
+        let a: Arc<dyn Embeddings> = Flaky::new("m", 2);
+        let b: Arc<dyn Embeddings> = Flaky::new("other", 2);
+        let c: Arc<dyn Embeddings> = Flaky::new("m", 3);
+        assert!(EmbeddingsRouter::new(vec![a.clone(), b], Routing::Priority).is_err());
+        assert!(EmbeddingsRouter::new(vec![a.clone(), c], Routing::Priority).is_err());
+        assert!(EmbeddingsRouter::new(vec![], Routing::Priority).is_err());
+        assert!(EmbeddingsRouter::new(vec![a.clone(), a], Routing::Priority).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_different_identities() -> anyhow::Result<()> {
+        let fake: Arc<dyn Embeddings> = Arc::new(FakeEmbeddings::new(4));
+        let seeded: Arc<dyn Embeddings> = Arc::new(FakeEmbeddings::new(4).with_seed(1));
+        assert!(EmbeddingsRouter::new(vec![fake.clone(), seeded], Routing::Priority).is_err());
+
+        let openai = |configure: fn(&mut OpenAIEmbedding)| -> Arc<dyn Embeddings> {
+            let mut embedding = OpenAIEmbedding::new("text-embedding-3-small");
+            configure(&mut embedding);
+            Arc::new(embedding)
+        };
+        let truncate = openai(|e| e.token_limit_policy = TokenLimitPolicy::Truncate);
+        let split = openai(|e| e.token_limit_policy = TokenLimitPolicy::SplitAndAverage);
+        let shorter = openai(|e| {
+            e.token_limit_policy = TokenLimitPolicy::Truncate;
+            e.max_tokens = 512;
+        });
+        assert!(EmbeddingsRouter::new(vec![truncate.clone(), split], Routing::Priority).is_err());
+        assert!(EmbeddingsRouter::new(vec![truncate.clone(), shorter], Routing::Priority).is_err());
+        let same = openai(|e| e.token_limit_policy = TokenLimitPolicy::Truncate);
+        assert!(EmbeddingsRouter::new(vec![truncate, same], Routing::Priority).is_ok());
+
+        let router = EmbeddingsRouter::new(vec![fake.clone(), fake.clone()], Routing::Priority)?;
+        assert_eq!(router.identity(), fake.identity());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_priority_fallback_and_circuit_breaker() -> anyhow::Result<()> {
+        let primary = Flaky::new("m", 2);
+        let secondary = Flaky::new("m", 2);
+        let mut router =
+            EmbeddingsRouter::new(vec![primary.clone(), secondary.clone()], Routing::Priority)?;
+        router.failure_threshold = 2;
+        router.cooldown = Duration::from_millis(100);
+
+        router.embed_query("a").await?;
+        assert_eq!((primary.calls(), secondary.calls()), (1, 0));
+
+        primary.failing.store(true, Ordering::SeqCst);
+        for _ in 0..4 {
+            router.embed_texts(&["a", "b"]).await?;
+        }
+        // 2回失敗した後はprimaryを試さない
+        assert_eq!((primary.calls(), secondary.calls()), (3, 4));
+
+        // cooldownが過ぎれば再びprimaryを試し、成功すれば元に戻る
+        primary.failing.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        router.embed_query("a").await?;
+        router.embed_query("a").await?;
+        assert_eq!((primary.calls(), secondary.calls()), (5, 4));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() -> anyhow::Result<()> {
+        let backends: Vec<Arc<Flaky>> = (0..3).map(|_| Flaky::new("m", 2)).collect();
+        let router = EmbeddingsRouter::new(
+            backends
+                .iter()
+                .map(|b| b.clone() as Arc<dyn Embeddings>)
+                .collect(),
+            Routing::RoundRobin,
+        )?;
+        for _ in 0..6 {
+            router.embed_query("a").await?;
+        }
+        assert!(backends.iter().all(|b| b.calls() == 2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_all_backends_fail() {
+        let a = Flaky::new("m", 2);
+        let b = Flaky::new("m", 2);
+        a.failing.store(true, Ordering::SeqCst);
+        b.failing.store(true, Ordering::SeqCst);
+        let router = EmbeddingsRouter::new(vec![a, b], Routing::Priority).unwrap();
+        let error = router.embed_query("a").await.unwrap_err();
+        assert!(error.to_string().contains("all 2 backends failed"));
+    }
+}
@@ -0,0 +1,172 @@
+use super::http::{self, RequestSettings};
+use super::Embeddings;
+
+/// # TextEmbeddingsInference
+///
+/// Hugging FaceのText Embeddings Inference(TEI)のサーバーの`/embed`でembeddingを生成する
+///
+/// TEIは起動時に指定した1つのモデルだけを動かすので、`model`はモデルの名前として使うだけでリクエストには含めない
+///
+/// モデルにプロンプトが設定されている場合は、`query_prompt_name`と`document_prompt_name`で
+/// queryとdocumentに付けるプロンプトを選べる
+#[derive(Debug, Clone)]
+pub struct TextEmbeddingsInference {
+    pub model: String,
+    /// 例: `http://localhost:8080`
+    pub api_base: String,
+    /// サーバーを`--api-key`付きで起動した場合に指定する
+    pub api_key: Option<String>,
+    /// ノルムを1にするかどうか
+    pub normalize: bool,
+    /// 最大長を超える入力を切り詰めるかどうか。`false`の場合はエラーになる
+    pub truncate: bool,
+    pub query_prompt_name: Option<String>,
+    pub document_prompt_name: Option<String>,
+    /// `batch_size`はサーバーの`--max-client-batch-size`以下にする
+    pub request: RequestSettings,
+}
+
+impl TextEmbeddingsInference {
+    pub fn new(api_base: &str, model: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            api_base: api_base.to_string(),
+            api_key: None,
+            normalize: true,
+            truncate: false,
+            query_prompt_name: None,
+            document_prompt_name: None,
+            request: RequestSettings::new(32, 4, 3),
+        }
+    }
+
+    async fn embed_batch(
+        &self,
+        inputs: &[&str],
+        prompt_name: Option<&str>,
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        let mut request = self
+            .request
+            .client
+            .post(format!("{}/embed", self.api_base.trim_end_matches('/')))
+            .json(&EmbedRequest {
+                inputs,
+                normalize: self.normalize,
+                truncate: self.truncate,
+                prompt_name,
+            });
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let response = self.request.send(request).await?;
+        let embeddings = response.json::<Vec<Vec<f32>>>().await?;
+        http::check_len(inputs.len(), &embeddings)?;
+        Ok(embeddings)
+    }
+
+    async fn embed_inputs(
+        &self,
+        texts: &[&str],
+        prompt_name: Option<&str>,
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.request
+            .embed_in_batches(texts, |batch| self.embed_batch(batch, prompt_name))
+            .await
+    }
+}
+
+#[derive(serde::Serialize)]
+struct EmbedRequest<'a> {
+    inputs: &'a [&'a str],
+    normalize: bool,
+    truncate: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_name: Option<&'a str>,
+}
+
+#[async_trait::async_trait]
+impl Embeddings for TextEmbeddingsInference {
+    async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.embed_inputs(texts, self.document_prompt_name.as_deref())
+            .await
+    }
+
+    async fn embed_query(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        self.embed_inputs(&[text], self.query_prompt_name.as_deref())
+            .await?
+            .pop()
+            .ok_or(anyhow::anyhow!("no embedding was returned for the query"))
+    }
+
+    fn model_name(&self) -> &str {
+        &self.model
+    }
+
+    fn identity(&self) -> String {
+        format!(
+            "{}(normalize={},document_prompt_name={:?})",
+            self.model, self.normalize, self.document_prompt_name
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::http::fixture;
+    use wiremock::matchers::{body_json, header, method, path};
+    use wiremock::{Mock, MockServer};
+
+    #[tokio::test]
+    async fn test_embed_with_fixture() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/embed"))
+            .and(header("authorization", "Bearer tei-key"))
+            .and(body_json(serde_json::json!({
+                "inputs": ["What is Deep Learning?", "Deep Learning is a subfield of ML"],
+                "normalize": true,
+                "truncate": false,
+                "prompt_name": "passage",
+            })))
+            .respond_with(fixture(include_str!(
+                "../../tests/fixtures/embeddings/tei_embed.json"
+            )))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/embed"))
+            .and(body_json(serde_json::json!({
+                "inputs": ["What is Deep Learning?"],
+                "normalize": true,
+                "truncate": false,
+                "prompt_name": "query",
+            })))
+            .respond_with(fixture(include_str!(
+                "../../tests/fixtures/embeddings/tei_embed_query.json"
+            )))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let embedding = TextEmbeddingsInference {
+            api_key: Some("tei-key".to_string()),
+            query_prompt_name: Some("query".to_string()),
+            document_prompt_name: Some("passage".to_string()),
+            ..TextEmbeddingsInference::new(&server.uri(), "BAAI/bge-small-en-v1.5")
+        };
+        let result = embedding
+            .embed_texts(&[
+                "What is Deep Learning?",
+                "Deep Learning is a subfield of ML",
+            ])
+            .await?;
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[1], vec![-0.0433, 0.0125, 0.0298, -0.0081]);
+        assert_eq!(
+            embedding.embed_query("What is Deep Learning?").await?,
+            vec![0.0512, -0.0094, 0.0371, 0.0188]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+use crate::tokenizer::cl100k_base;
+use crate::vectorstores::utils;
+use std::ops::Range;
+
+/// # TokenLimitPolicy
+///
+/// 入力がモデルの最大トークン数を超える場合の扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TokenLimitPolicy {
+    /// 先頭から最大トークン数までだけを使う
+    Truncate,
+    /// 最大トークン数ずつに分けてそれぞれのembeddingを作り、トークン数で重み付けした平均を返す
+    #[default]
+    SplitAndAverage,
+    /// 何番目の入力が超えているかを含むエラーを返す
+    Error,
+}
+
+/// `texts`のうち`max_tokens`を超えるものを`policy`に従って分ける
+///
+/// モデルに送る入力と、元の入力ごとの各断片のトークン数(`combine`と`batches`で使う)を返す
+pub(crate) fn split_inputs(
+    texts: &[&str],
+    max_tokens: usize,
+    policy: TokenLimitPolicy,
+) -> anyhow::Result<(Vec<String>, Vec<Vec<usize>>)> {
+    let mut inputs = Vec::with_capacity(texts.len());
+    let mut weights = Vec::with_capacity(texts.len());
+    for (index, text) in texts.iter().enumerate() {
+        let tokens = cl100k_base().encode_ordinary(text);
+        if tokens.len() <= max_tokens {
+            inputs.push(text.to_string());
+            weights.push(vec![tokens.len()]);
+            continue;
+        }
+        let pieces = match policy {
+            TokenLimitPolicy::Error => anyhow::bail!(
+                "input {} has {} tokens, which exceeds the limit of {}",
+                index,
+                tokens.len(),
+                max_tokens
+            ),
+            TokenLimitPolicy::Truncate => {
+                let mut pieces = split_tokens(&tokens, max_tokens)?;
+                pieces.truncate(1);
+                pieces
+            }
+            TokenLimitPolicy::SplitAndAverage => split_tokens(&tokens, max_tokens)?,
+        };
+        let (texts, counts): (Vec<String>, Vec<usize>) = pieces.into_iter().unzip();
+        inputs.extend(texts);
+        weights.push(counts);
+    }
+    Ok((inputs, weights))
+}
+
+/// `split_inputs`で分けた入力のembeddingを元の入力ごとにまとめる
+///
+/// 複数に分けた入力は、トークン数で重み付けした平均をノルム1にしたものになる
+pub(crate) fn combine(embeddings: Vec<Vec<f32>>, weights: &[Vec<usize>]) -> Vec<Vec<f32>> {
+    let mut embeddings = embeddings.into_iter();
+    weights
+        .iter()
+        .map(|weights| {
+            if weights.len() == 1 {
+                return embeddings.next().unwrap_or_default();
+            }
+            let total = weights.iter().sum::<usize>() as f32;
+            let mut average: Vec<f32> = Vec::new();
+            for (embedding, weight) in embeddings.by_ref().take(weights.len()).zip(weights) {
+                average.resize(embedding.len(), 0.0);
+                for (a, e) in average.iter_mut().zip(&embedding) {
+                    *a += e * *weight as f32 / total;
+                }
+            }
+            let norm = utils::norm(&average);
+            if norm > 0.0 {
+                average.iter_mut().for_each(|a| *a /= norm);
+            }
+            average
+        })
+        .collect()
+}
+
+/// `split_inputs`で分けた入力を、`batch_size`件以下かつ合計トークン数が`max_batch_tokens`以下になるように
+/// 先頭から順にまとめ、各リクエストに含める範囲を返す
+///
+/// 1つで`max_batch_tokens`を超える入力は単独のリクエストにする
+pub(crate) fn batches(
+    weights: &[Vec<usize>],
+    batch_size: usize,
+    max_batch_tokens: usize,
+) -> Vec<Range<usize>> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut tokens = 0;
+    for (end, count) in weights.iter().flatten().enumerate() {
+        if end > start && (end - start >= batch_size.max(1) || tokens + count > max_batch_tokens) {
+            batches.push(start..end);
+            start = end;
+            tokens = 0;
+        }
+        tokens += count;
+    }
+    let len = weights.iter().map(Vec::len).sum();
+    if start < len {
+        batches.push(start..len);
+    }
+    batches
+}
+
+/// `tokens`を`max_tokens`以下ずつに分けて、文字列とトークン数の組にする
+///
+/// マルチバイト文字の途中で切るとdecodeできないので、その場合はdecodeできるところまで短くする
+fn split_tokens(tokens: &[usize], max_tokens: usize) -> anyhow::Result<Vec<(String, usize)>> {
+    let bpe = cl100k_base();
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    while start < tokens.len() {
+        let mut end = (start + max_tokens.max(1)).min(tokens.len());
+        let text = loop {
+            match bpe.decode(tokens[start..end].to_vec()) {
+                Ok(text) => break text,
+                Err(_) if end > start + 1 => end -= 1,
+                Err(error) => return Err(error),
+            }
+        };
+        pieces.push((text, end - start));
+        start = end;
+    }
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::count_tokens;
+
+    #[test]
+    fn test_split_inputs() -> anyhow::Result<()> {
+        let long = ["hello world"; 10].join(" ");
+        let texts = ["short", long.as_str()];
+        assert_eq!(count_tokens(&long), 20);
+
+        let (inputs, weights) = split_inputs(&texts, 8, TokenLimitPolicy::SplitAndAverage)?;
+        assert_eq!(inputs.len(), 4);
+        assert_eq!(inputs[0], "short");
+        assert_eq!(inputs[1..].concat(), long);
+        assert_eq!(weights, vec![vec![1], vec![8, 8, 4]]);
+
+        let (inputs, weights) = split_inputs(&texts, 8, TokenLimitPolicy::Truncate)?;
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(count_tokens(&inputs[1]), 8);
+        assert!(long.starts_with(&inputs[1]));
+        assert_eq!(weights, vec![vec![1], vec![8]]);
+
+        let error = split_inputs(&texts, 8, TokenLimitPolicy::Error).unwrap_err();
+        assert!(error.to_string().contains("input 1 has 20 tokens"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_batches() {
+        let weights = vec![vec![3], vec![4, 4, 1], vec![10], vec![2], vec![2]];
+        // トークン数の上限で区切る
+        assert_eq!(batches(&weights, 100, 8), vec![0..2, 2..4, 4..5, 5..7]);
+        // 件数の上限で区切る
+        assert_eq!(batches(&weights, 3, 100), vec![0..3, 3..6, 6..7]);
+        assert!(batches(&[], 3, 100).is_empty());
+    }
+
+    #[test]
+    fn test_split_multibyte() -> anyhow::Result<()> {
+        // 1文字が複数トークンになる文字を含んでいても、文字の途中では切らない
+        let text = "🦜🔗".repeat(20);
+        let (inputs, _) = split_inputs(&[text.as_str()], 5, TokenLimitPolicy::SplitAndAverage)?;
+        assert!(inputs.len() > 1);
+        assert_eq!(inputs.concat(), text);
+        Ok(())
+    }
+
+    #[test]
+    fn test_combine() {
+        let embeddings = vec![vec![1.0, 0.0], vec![3.0, 0.0], vec![0.0, 3.0]];
+        let combined = combine(embeddings, &[vec![1], vec![3, 1]]);
+        assert_eq!(combined[0], vec![1.0, 0.0]);
+        // (3 * 3/4, 3 * 1/4) = (2.25, 0.75)をノルム1にする
+        let norm = (2.25f32 * 2.25 + 0.75 * 0.75).sqrt();
+        assert!((combined[1][0] - 2.25 / norm).abs() < 1e-6);
+        assert!((combined[1][1] - 0.75 / norm).abs() < 1e-6);
+    }
+}
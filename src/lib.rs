@@ -6,4 +6,5 @@ pub mod indexes;
 pub mod llms;
 pub mod schema;
 pub mod text_splitter;
+pub mod tokenizer;
 pub mod vectorstores;
@@ -1,9 +1,20 @@
-#[derive(Debug, Clone)]
-pub struct Document<M = EmptyMetadata> {
+use std::collections::HashMap;
+
+pub use serde_json::Value;
+
+/// Documentに付随するメタデータ
+///
+/// keyは文字列、valueはJSONとして表現できる任意の値
+/// 例: `source`, `channel`, `ts`, `page`, `author`
+pub type Metadata = HashMap<String, Value>;
+
+#[derive(Debug, Clone, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+pub struct Document {
     pub page_content: String,
     pub lookup_str: String,
     pub lookup_index: usize,
-    pub metadata: Option<M>,
+    #[serde(default)]
+    pub metadata: Metadata,
 }
 
 impl Document {
@@ -12,37 +23,35 @@ impl Document {
             page_content: page_content.to_string(),
             lookup_str: "".to_string(),
             lookup_index,
-            metadata: None,
+            metadata: Metadata::new(),
         }
     }
+
+    /// metadataを1件追加したDocumentを返す
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
     pub fn paragraphs(&self) -> Vec<&str> {
         self.page_content.split("\n\n").collect()
     }
 
     pub fn summary(&self) -> Option<&str> {
-        self.paragraphs().first().map(|p| *p)
+        self.paragraphs().first().copied()
     }
 }
 
-impl Default for Document {
-    fn default() -> Self {
-        Self {
-            page_content: "".to_string(),
-            lookup_str: "".to_string(),
-            lookup_index: 0,
-            metadata: None,
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
-pub struct EmptyMetadata;
-
-#[derive(Debug, Clone)]
-pub struct VectorMetadata(pub Vec<f32>);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl From<Vec<f32>> for VectorMetadata {
-    fn from(v: Vec<f32>) -> Self {
-        Self(v)
+    #[test]
+    fn test_with_metadata() {
+        let doc = Document::new("hello", 0)
+            .with_metadata("source", "https://example.com")
+            .with_metadata("page", 3);
+        assert_eq!(doc.metadata["source"], "https://example.com");
+        assert_eq!(doc.metadata["page"], 3);
     }
 }
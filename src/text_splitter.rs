@@ -1,23 +1,22 @@
-use crate::schema::{Document, EmptyMetadata};
+use crate::schema::{Document, Metadata};
+use crate::tokenizer;
+use std::fmt;
+use std::sync::Arc;
 
-pub trait TextSplitter<M: Clone = EmptyMetadata>
-where
-    Document<M>: Default,
-    Vec<std::option::Option<M>>: FromIterator<std::option::Option<EmptyMetadata>>,
-{
+pub trait TextSplitter {
     fn split_text(&self, text: &str) -> Vec<String>;
-    fn split_document(&self, documents: Vec<Document>) -> Vec<Document<M>> {
-        let texts = documents
-            .iter()
-            .map(|doc| doc.page_content.clone())
-            .collect();
-        let metadatas = documents.iter().map(|doc| doc.metadata.clone()).collect();
+    /// 各chunkには元のDocumentのmetadataが引き継がれる
+    fn split_document(&self, documents: Vec<Document>) -> Vec<Document> {
+        let (texts, metadatas) = documents
+            .into_iter()
+            .map(|doc| (doc.page_content, doc.metadata))
+            .unzip();
         self.create_documents(texts, metadatas)
     }
-    fn create_documents(&self, texts: Vec<String>, metadatas: Vec<Option<M>>) -> Vec<Document<M>> {
+    fn create_documents(&self, texts: Vec<String>, metadatas: Vec<Metadata>) -> Vec<Document> {
         let mut documents = Vec::new();
         for (i, text) in texts.into_iter().enumerate() {
-            let metadata = &metadatas[i];
+            let metadata = metadatas.get(i).cloned().unwrap_or_default();
             for chunk in self.split_text(&text) {
                 documents.push(Document {
                     page_content: chunk,
@@ -38,14 +37,50 @@ where
     }
 }
 
+/// # LengthFunction
+///
+/// `chunk_size`と`chunk_overlap`をどの単位で数えるか
+#[derive(Clone, Default)]
+pub enum LengthFunction {
+    /// 文字数(`char`の数)
+    #[default]
+    Characters,
+    /// cl100k_baseでのトークン数。OpenAIのembeddingモデルのトークン数の上限に合わせる場合に使う
+    Tokens,
+    Custom(Arc<dyn Fn(&str) -> usize + Send + Sync>),
+}
+
+impl LengthFunction {
+    pub fn len(&self, text: &str) -> usize {
+        match self {
+            LengthFunction::Characters => text.chars().count(),
+            LengthFunction::Tokens => tokenizer::count_tokens(text),
+            LengthFunction::Custom(f) => f(text),
+        }
+    }
+}
+
+impl fmt::Debug for LengthFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LengthFunction::Characters => write!(f, "Characters"),
+            LengthFunction::Tokens => write!(f, "Tokens"),
+            LengthFunction::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
 /// # RecursiveCharacterTextSplitter
 ///
 /// https://github.com/hwchase17/langchain/blob/c2d1d903fa35b91018b4d777db2b008fcbaa9fbc/langchain/text_splitter.py#L221
+///
+/// 長さは`length_function`で数える。デフォルトは文字数
 #[derive(Debug, Clone)]
 pub struct RecursiveCharacterTextSplitter {
     chunk_size: usize,
     chunk_overlap: usize,
     separators: Vec<String>,
+    length_function: LengthFunction,
 }
 
 impl RecursiveCharacterTextSplitter {
@@ -56,33 +91,55 @@ impl RecursiveCharacterTextSplitter {
             chunk_size,
             chunk_overlap,
             separators,
+            length_function: LengthFunction::default(),
         }
     }
 
+    /// `chunk_size`と`chunk_overlap`を数える単位を変更する
+    pub fn with_length_function(mut self, length_function: LengthFunction) -> Self {
+        self.length_function = length_function;
+        self
+    }
+
     fn merge_splits(&self, splits: Vec<String>, separator: &str) -> Vec<String> {
         let mut docs = Vec::new();
-        let mut current_doc = Vec::new();
+        let mut current_doc: Vec<(String, usize)> = Vec::new();
         let mut total = 0;
+        // つなぐときに間に入るseparatorの長さも数える
+        let separator_len = self.length_function.len(separator);
         for d in &splits {
-            let len = d.len();
-            if total + len >= self.chunk_size {
+            let len = self.length_function.len(d);
+            let joined_len = |current_doc: &[(String, usize)]| {
+                len + if current_doc.is_empty() {
+                    0
+                } else {
+                    separator_len
+                }
+            };
+            if total + joined_len(&current_doc) >= self.chunk_size {
                 // Rustでは組み込みのロギング機能がないので、logクレート等を使用する
                 // if total > self.chunk_size {}
-                if !current_doc.clone().is_empty() {
-                    if let Some(doc) = self.join_docs(current_doc.clone(), separator) {
+                if !current_doc.is_empty() {
+                    let texts = current_doc.iter().map(|(d, _)| d.clone()).collect();
+                    if let Some(doc) = self.join_docs(texts, separator) {
                         docs.push(doc);
                     }
-                    while total > self.chunk_overlap || (total + len > self.chunk_size && total > 0)
+                    while total > self.chunk_overlap
+                        || (total + joined_len(&current_doc) > self.chunk_size && total > 0)
                     {
-                        total -= &current_doc[0].len();
+                        total -= current_doc[0].1;
+                        if current_doc.len() > 1 {
+                            total -= separator_len;
+                        }
                         current_doc.remove(0);
                     }
                 }
             }
-            current_doc.push(d.clone());
-            total += len;
+            total += joined_len(&current_doc);
+            current_doc.push((d.clone(), len));
         }
-        if let Some(doc) = self.join_docs(current_doc, separator) {
+        let texts = current_doc.into_iter().map(|(d, _)| d).collect();
+        if let Some(doc) = self.join_docs(texts, separator) {
             docs.push(doc);
         }
         docs
@@ -100,6 +157,7 @@ impl Default for RecursiveCharacterTextSplitter {
                 " ".to_string(),
                 "".to_string(),
             ],
+            length_function: LengthFunction::default(),
         }
     }
 }
@@ -121,7 +179,7 @@ impl TextSplitter for RecursiveCharacterTextSplitter {
         };
         let mut good_splits = Vec::new();
         for s in splits {
-            if s.len() < self.chunk_size {
+            if self.length_function.len(&s) < self.chunk_size {
                 good_splits.push(s);
             } else {
                 if !good_splits.is_empty() {
@@ -137,3 +195,60 @@ impl TextSplitter for RecursiveCharacterTextSplitter {
         final_chunks
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_document_propagates_metadata() {
+        let splitter = RecursiveCharacterTextSplitter::new(10, 0, None);
+        let docs = vec![
+            Document::new("aaaa bbbb cccc dddd", 0).with_metadata("source", "a.txt"),
+            Document::new("eeee", 1).with_metadata("source", "b.txt"),
+        ];
+        let chunks = splitter.split_document(docs);
+        assert!(chunks.len() > 2);
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(rest.iter().all(|c| c.metadata["source"] == "a.txt"));
+        assert_eq!(last.metadata["source"], "b.txt");
+    }
+
+    #[test]
+    fn test_split_japanese_by_characters() {
+        // 1文字3バイトなので、バイト数で数えると10文字未満でも分割されてしまう
+        let splitter = RecursiveCharacterTextSplitter::new(10, 0, None);
+        let chunks = splitter.split_text("吾輩は猫である。\n名前はまだ無い。");
+        assert_eq!(chunks, vec!["吾輩は猫である。", "名前はまだ無い。"]);
+    }
+
+    #[test]
+    fn test_split_by_tokens() {
+        let text = ["hello world"; 50].join(" ");
+        let splitter = RecursiveCharacterTextSplitter::new(20, 4, None)
+            .with_length_function(LengthFunction::Tokens);
+        let chunks = splitter.split_text(&text);
+        assert!(chunks.len() > 1);
+        assert!(chunks
+            .iter()
+            .all(|chunk| tokenizer::count_tokens(chunk) <= 20));
+
+        let splitter = RecursiveCharacterTextSplitter::new(3, 0, None).with_length_function(
+            LengthFunction::Custom(Arc::new(|text| text.split_whitespace().count())),
+        );
+        assert_eq!(splitter.split_text("a b c d e"), vec!["a b", "c d", "e"]);
+    }
+
+    #[test]
+    fn test_split_counts_separators() {
+        // "\n"も1トークンなので、数えないとchunkが倍近い長さになる
+        let text = ["a"; 100].join("\n");
+        let splitter = RecursiveCharacterTextSplitter::new(20, 0, None)
+            .with_length_function(LengthFunction::Tokens);
+        let chunks = splitter.split_text(&text);
+        assert!(chunks.len() > 1);
+        assert!(chunks
+            .iter()
+            .all(|chunk| tokenizer::count_tokens(chunk) <= 20));
+    }
+}
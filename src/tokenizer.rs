@@ -0,0 +1,27 @@
+use std::sync::OnceLock;
+use tiktoken_rs::CoreBPE;
+
+/// OpenAIのembeddingモデル(`text-embedding-ada-002`, `text-embedding-3-*`)と同じcl100k_baseのtokenizer
+///
+/// 作るのに時間がかかるので、最初に使うときに1回だけ作って使い回す
+pub fn cl100k_base() -> &'static CoreBPE {
+    static BPE: OnceLock<CoreBPE> = OnceLock::new();
+    BPE.get_or_init(|| tiktoken_rs::cl100k_base().expect("cl100k_base is bundled with tiktoken-rs"))
+}
+
+/// cl100k_baseでのトークン数
+pub fn count_tokens(text: &str) -> usize {
+    cl100k_base().encode_ordinary(text).len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_tokens() {
+        assert_eq!(count_tokens(""), 0);
+        assert_eq!(count_tokens("hello world"), 2);
+        assert!(count_tokens("こんにちは世界") > 1);
+    }
+}
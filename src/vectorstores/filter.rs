@@ -0,0 +1,220 @@
+use crate::schema::{Metadata, Value};
+use std::cmp::Ordering;
+
+/// # Filter
+///
+/// Documentのmetadataに対する絞り込み条件
+///
+/// バックエンドに依存しないただのデータなので、InMemoryVectorStore以外のstoreでは
+/// これを各DBのクエリ言語に変換して使う
+///
+/// ```
+/// use langchain::vectorstores::Filter;
+///
+/// // channelがC017Y386TNKで、tsが1690000000以上のもの
+/// // SlackLoaderはtsを文字列で保存するので、文字列同士の辞書順で比較する
+/// let filter = Filter::and(vec![
+///     Filter::eq("channel", "C017Y386TNK"),
+///     Filter::gte("ts", "1690000000"),
+/// ]);
+/// ```
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    /// `metadata[key] == value`。数値同士は`3`と`3.0`のように型が違っても数値として比べる
+    Eq(String, Value),
+    /// `metadata[key]`が`values`のいずれかと等しい
+    In(String, Vec<Value>),
+    /// `metadata[key]`が範囲内にある。指定されていない境界は無視する
+    ///
+    /// 数値同士は数値として、文字列同士は辞書順で比較し、それ以外の組み合わせはマッチしない
+    Range {
+        key: String,
+        gt: Option<Value>,
+        gte: Option<Value>,
+        lt: Option<Value>,
+        lte: Option<Value>,
+    },
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn eq(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Filter::Eq(key.into(), value.into())
+    }
+
+    pub fn is_in<V: Into<Value>>(
+        key: impl Into<String>,
+        values: impl IntoIterator<Item = V>,
+    ) -> Self {
+        Filter::In(key.into(), values.into_iter().map(Into::into).collect())
+    }
+
+    pub fn gt(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Self::range(key, Some(value.into()), None, None, None)
+    }
+
+    pub fn gte(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Self::range(key, None, Some(value.into()), None, None)
+    }
+
+    pub fn lt(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Self::range(key, None, None, Some(value.into()), None)
+    }
+
+    pub fn lte(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Self::range(key, None, None, None, Some(value.into()))
+    }
+
+    pub fn range(
+        key: impl Into<String>,
+        gt: Option<Value>,
+        gte: Option<Value>,
+        lt: Option<Value>,
+        lte: Option<Value>,
+    ) -> Self {
+        Filter::Range {
+            key: key.into(),
+            gt,
+            gte,
+            lt,
+            lte,
+        }
+    }
+
+    pub fn and(filters: Vec<Filter>) -> Self {
+        Filter::And(filters)
+    }
+
+    pub fn or(filters: Vec<Filter>) -> Self {
+        Filter::Or(filters)
+    }
+
+    /// metadataがこの条件にマッチするかどうか
+    ///
+    /// keyが存在しない場合、Eq/In/Rangeはマッチしない
+    pub fn matches(&self, metadata: &Metadata) -> bool {
+        match self {
+            Filter::Eq(key, value) => metadata
+                .get(key)
+                .is_some_and(|actual| equals(actual, value)),
+            Filter::In(key, values) => metadata
+                .get(key)
+                .is_some_and(|actual| values.iter().any(|value| equals(actual, value))),
+            Filter::Range {
+                key,
+                gt,
+                gte,
+                lt,
+                lte,
+            } => {
+                let Some(actual) = metadata.get(key) else {
+                    return false;
+                };
+                let check = |bound: &Option<Value>, accept: fn(Ordering) -> bool| {
+                    bound
+                        .as_ref()
+                        .is_none_or(|b| compare(actual, b).is_some_and(accept))
+                };
+                check(gt, Ordering::is_gt)
+                    && check(gte, Ordering::is_ge)
+                    && check(lt, Ordering::is_lt)
+                    && check(lte, Ordering::is_le)
+            }
+            Filter::And(filters) => filters.iter().all(|f| f.matches(metadata)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(metadata)),
+            Filter::Not(filter) => !filter.matches(metadata),
+        }
+    }
+}
+
+/// `!filter`で条件を反転する
+impl std::ops::Not for Filter {
+    type Output = Filter;
+
+    fn not(self) -> Self::Output {
+        Filter::Not(Box::new(self))
+    }
+}
+
+/// 数値同士は`compare`で、それ以外はそのまま比べる
+fn equals(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(_), Value::Number(_)) => compare(a, b) == Some(Ordering::Equal),
+        _ => a == b,
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Document;
+
+    fn metadata() -> Metadata {
+        Document::new("", 0)
+            .with_metadata("channel", "C017Y386TNK")
+            .with_metadata("page", 3)
+            .with_metadata("domain", "example.com")
+            .metadata
+    }
+
+    #[test]
+    fn test_eq_and_in() {
+        let metadata = metadata();
+        assert!(Filter::eq("channel", "C017Y386TNK").matches(&metadata));
+        assert!(!Filter::eq("channel", "C000").matches(&metadata));
+        assert!(!Filter::eq("missing", "C017Y386TNK").matches(&metadata));
+        assert!(Filter::is_in("domain", ["example.com", "example.org"]).matches(&metadata));
+        assert!(!Filter::is_in("domain", ["example.org"]).matches(&metadata));
+        // 整数と小数も数値として比べる
+        assert!(Filter::eq("page", 3.0).matches(&metadata));
+        assert!(Filter::is_in("page", [1.0, 3.0]).matches(&metadata));
+        assert!(!Filter::eq("page", "3").matches(&metadata));
+    }
+
+    #[test]
+    fn test_range() {
+        let metadata = metadata();
+        assert!(Filter::gte("page", 3).matches(&metadata));
+        assert!(!Filter::gt("page", 3).matches(&metadata));
+        assert!(
+            Filter::range("page", Some(1.into()), None, Some(10.into()), None).matches(&metadata)
+        );
+        assert!(Filter::lt("domain", "f").matches(&metadata));
+        // 型が違うものは比較できないのでマッチしない
+        assert!(!Filter::lt("page", "10").matches(&metadata));
+
+        // SlackLoaderのtsは文字列
+        let slack = Document::new("", 0)
+            .with_metadata("ts", "1690000001.000200")
+            .metadata;
+        assert!(Filter::gte("ts", "1690000000").matches(&slack));
+        assert!(!Filter::gte("ts", 1690000000).matches(&slack));
+    }
+
+    #[test]
+    fn test_and_or_not() {
+        let metadata = metadata();
+        assert!(Filter::and(vec![
+            Filter::eq("channel", "C017Y386TNK"),
+            Filter::lte("page", 3),
+        ])
+        .matches(&metadata));
+        assert!(
+            Filter::or(vec![Filter::eq("channel", "C000"), Filter::eq("page", 3)])
+                .matches(&metadata)
+        );
+        assert!((!Filter::eq("channel", "C000")).matches(&metadata));
+        assert!((!Filter::eq("missing", 1)).matches(&metadata));
+    }
+}
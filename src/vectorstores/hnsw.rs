@@ -0,0 +1,622 @@
+use super::*;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// # HnswConfig
+///
+/// HNSWのグラフ構築・検索のパラメータ
+///
+/// https://arxiv.org/abs/1603.09320
+#[derive(Debug, Clone)]
+pub struct HnswConfig {
+    /// 各ノードが持つ近傍の数。レイヤー0では最大でこの2倍まで持つ
+    pub m: usize,
+    /// 挿入時に探索する候補数。大きいほどグラフの質(recall)が上がるが挿入が遅くなる
+    pub ef_construction: usize,
+    /// 検索時に探索する候補数。大きいほどrecallが上がるが検索が遅くなる
+    pub ef_search: usize,
+    /// ノード間の距離とスコアの計算方法
+    pub distance_strategy: DistanceStrategy,
+}
+
+impl Default for HnswConfig {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 200,
+            ef_search: 64,
+            distance_strategy: DistanceStrategy::default(),
+        }
+    }
+}
+
+struct Node {
+    record: VectorRecord,
+    /// レイヤーごとの近傍ノードのindex
+    neighbors: Vec<Vec<usize>>,
+    /// 削除済みのノードは検索結果には出さないが、グラフの経路としては残す
+    deleted: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f32,
+    index: usize,
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.index.cmp(&other.index))
+    }
+}
+
+/// # HnswVectorStore
+///
+/// HNSW(Hierarchical Navigable Small World)グラフによる近似最近傍探索のVectorStore
+///
+/// InMemoryVectorStoreのような全件比較をしないので、件数が増えても検索が速い。
+/// その代わり結果は近似で、recallは`HnswConfig`のパラメータで調整する
+///
+/// スコアはInMemoryVectorStoreと同じく`HnswConfig::distance_strategy`で計算する
+///
+/// グラフは`RwLock`の中にあり、検索は読み込みロック、追加・削除は書き込みロックで行う
+pub struct HnswVectorStore<E>
+where
+    E: Embeddings + Clone + Send + Sync + 'static,
+{
+    embeddings: Option<Arc<E>>,
+    graph: RwLock<Graph>,
+}
+
+struct Graph {
+    config: HnswConfig,
+    nodes: Vec<Node>,
+    ids: HashMap<String, usize>,
+    entry_point: Option<usize>,
+    rng_state: u64,
+}
+
+impl<E> HnswVectorStore<E>
+where
+    E: Embeddings + Clone + Send + Sync + 'static,
+{
+    pub fn new(embeddings: E, config: HnswConfig) -> Self {
+        HnswVectorStore {
+            embeddings: Some(Arc::new(embeddings)),
+            graph: RwLock::new(Graph {
+                config,
+                nodes: Vec::new(),
+                ids: HashMap::new(),
+                entry_point: None,
+                rng_state: 0x2545_f491_4f6c_dd1d,
+            }),
+        }
+    }
+
+    pub async fn from_document(
+        documents: Vec<Document>,
+        embeddings: E,
+        config: HnswConfig,
+    ) -> anyhow::Result<Self> {
+        let store = Self::new(embeddings, config);
+        store.add_document(documents, None).await?;
+        Ok(store)
+    }
+
+    /// 削除されていないレコードの数
+    pub fn len(&self) -> usize {
+        self.read().ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().ids.is_empty()
+    }
+
+    /// 削除済みのノードを取り除いてグラフを作り直す
+    ///
+    /// 削除を繰り返すとグラフに不要なノードが溜まって検索が遅くなるので、適宜呼ぶ。
+    /// 作り直している間は検索もブロックされる
+    pub fn compact(&self) {
+        let mut graph = self.write();
+        let nodes = std::mem::take(&mut graph.nodes);
+        graph.ids.clear();
+        graph.entry_point = None;
+        for node in nodes.into_iter().filter(|node| !node.deleted) {
+            graph.insert(node.record);
+        }
+    }
+
+    /// ロック中にpanicしたスレッドがあっても、グラフは常に検索できる状態なのでそのまま使う
+    fn read(&self) -> RwLockReadGuard<'_, Graph> {
+        self.graph.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Graph> {
+        self.graph.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Graph {
+    /// ノードが無い場合はNone
+    fn dimension(&self) -> Option<usize> {
+        self.entry_point
+            .map(|index| self.nodes[index].record.embedding.len())
+    }
+
+    /// `similarity`の符号を反転したもの。スコア(relevance)は飽和して区別できなくなることがあるので使わない
+    fn distance(&self, query: &[f32], index: usize) -> f32 {
+        -self
+            .config
+            .distance_strategy
+            .similarity(query, &self.nodes[index].record.embedding)
+    }
+
+    fn max_neighbors(&self, level: usize) -> usize {
+        if level == 0 {
+            self.config.m * 2
+        } else {
+            self.config.m
+        }
+    }
+
+    /// レベルは指数分布に従って決める(上のレイヤーほどノードが少なくなる)
+    fn random_level(&mut self) -> usize {
+        // xorshift64*
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        let random = self.rng_state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        let uniform = ((random >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let level_mult = 1.0 / (self.config.m.max(2) as f64).ln();
+        (-uniform.ln() * level_mult).floor() as usize
+    }
+
+    /// 1つのレイヤー内で`query`に近いノードを最大`ef`個、近い順に返す
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entry_points: &[usize],
+        ef: usize,
+        level: usize,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<usize> = entry_points.iter().copied().collect();
+        // 次に調べる候補(近い順に取り出す)
+        let mut candidates: BinaryHeap<Reverse<Candidate>> = BinaryHeap::new();
+        // 見つかった近傍(遠い順に取り出す)
+        let mut results: BinaryHeap<Candidate> = BinaryHeap::new();
+        for &index in entry_points {
+            let candidate = Candidate {
+                distance: self.distance(query, index),
+                index,
+            };
+            candidates.push(Reverse(candidate));
+            results.push(candidate);
+        }
+
+        while let Some(Reverse(current)) = candidates.pop() {
+            let furthest = results.peek().map_or(f32::INFINITY, |c| c.distance);
+            if current.distance > furthest && results.len() >= ef {
+                break;
+            }
+            for &neighbor in &self.nodes[current.index].neighbors[level] {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let distance = self.distance(query, neighbor);
+                let furthest = results.peek().map_or(f32::INFINITY, |c| c.distance);
+                if results.len() < ef || distance < furthest {
+                    let candidate = Candidate {
+                        distance,
+                        index: neighbor,
+                    };
+                    candidates.push(Reverse(candidate));
+                    results.push(candidate);
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+        results.into_sorted_vec()
+    }
+
+    /// 最上位のレイヤーから`target_level`の1つ上まで貪欲に降りて、入口になるノードを返す
+    fn descend(&self, query: &[f32], target_level: usize) -> Option<usize> {
+        let entry = self.entry_point?;
+        let top_level = self.nodes[entry].neighbors.len() - 1;
+        let mut current = entry;
+        for level in (target_level + 1..=top_level).rev() {
+            current = self.search_layer(query, &[current], 1, level)[0].index;
+        }
+        Some(current)
+    }
+
+    /// 既に同じidのノードがあれば、それを削除済みにして新しいノードを追加する
+    fn insert(&mut self, record: VectorRecord) {
+        let level = self.random_level();
+        let index = self.nodes.len();
+        let query = record.embedding.clone();
+        if let Some(old) = self.ids.insert(record.id.clone(), index) {
+            self.nodes[old].deleted = true;
+        }
+        self.nodes.push(Node {
+            record,
+            neighbors: vec![Vec::new(); level + 1],
+            deleted: false,
+        });
+
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(index);
+            return;
+        };
+        let top_level = self.nodes[entry_point].neighbors.len() - 1;
+        let mut entry_points = vec![self.descend(&query, level).unwrap_or(entry_point)];
+        for level in (0..=level.min(top_level)).rev() {
+            let found =
+                self.search_layer(&query, &entry_points, self.config.ef_construction, level);
+            let neighbors: Vec<usize> = found
+                .iter()
+                .take(self.config.m)
+                .map(|candidate| candidate.index)
+                .collect();
+            for &neighbor in &neighbors {
+                self.nodes[neighbor].neighbors[level].push(index);
+                if self.nodes[neighbor].neighbors[level].len() > self.max_neighbors(level) {
+                    self.shrink_neighbors(neighbor, level);
+                }
+            }
+            self.nodes[index].neighbors[level] = neighbors;
+            entry_points = found.into_iter().map(|candidate| candidate.index).collect();
+        }
+        if level > top_level {
+            self.entry_point = Some(index);
+        }
+    }
+
+    /// 近傍が多すぎる場合、近いものだけを残す
+    fn shrink_neighbors(&mut self, index: usize, level: usize) {
+        let embedding = self.nodes[index].record.embedding.clone();
+        let mut neighbors: Vec<Candidate> = self.nodes[index].neighbors[level]
+            .iter()
+            .map(|&neighbor| Candidate {
+                distance: self.distance(&embedding, neighbor),
+                index: neighbor,
+            })
+            .collect();
+        neighbors.sort();
+        neighbors.truncate(self.max_neighbors(level));
+        self.nodes[index].neighbors[level] = neighbors.into_iter().map(|c| c.index).collect();
+    }
+
+    /// `filter`で絞り込んだ結果が`k`件に満たない場合は、探索範囲を広げて探し直す
+    fn search(
+        &self,
+        embedding: &[f32],
+        k: usize,
+        filter: Option<&Filter>,
+        score_threshold: Option<f32>,
+    ) -> anyhow::Result<Vec<(VectorRecord, f32)>> {
+        if let Some(dimension) = self.dimension() {
+            if embedding.len() != dimension {
+                anyhow::bail!(
+                    "query dimension mismatch: expected {}, got {}",
+                    dimension,
+                    embedding.len()
+                );
+            }
+        }
+        let Some(entry) = self.descend(embedding, 0) else {
+            return Ok(Vec::new());
+        };
+        // 削除済みを含めてもノードの数より多くは見つからない
+        let k = k.min(self.nodes.len());
+        let mut ef = self.config.ef_search.max(k);
+        loop {
+            let found = self.search_layer(embedding, &[entry], ef, 0);
+            // efより少ない数しか見つからない場合、到達できるノードは全て調べている
+            let exhausted = found.len() < ef || ef >= self.nodes.len();
+            let matched: Vec<Candidate> = found
+                .into_iter()
+                .filter(|candidate| {
+                    let node = &self.nodes[candidate.index];
+                    !node.deleted
+                        && filter.is_none_or(|f| f.matches(&node.record.document.metadata))
+                })
+                .take(k)
+                .collect();
+            if matched.len() >= k || exhausted {
+                return Ok(matched
+                    .into_iter()
+                    .map(|candidate| {
+                        let score = self
+                            .config
+                            .distance_strategy
+                            .relevance_from_similarity(-candidate.distance);
+                        (candidate.index, score)
+                    })
+                    .filter(|(_, score)| {
+                        score_threshold.is_none_or(|threshold| *score >= threshold)
+                    })
+                    .map(|(index, score)| (self.nodes[index].record.clone(), score))
+                    .collect());
+            }
+            ef *= 2;
+        }
+    }
+
+    fn delete(&mut self, ids: Vec<String>) -> DeleteResult {
+        let mut result = DeleteResult::default();
+        for id in ids {
+            if let Some(index) = self.ids.remove(&id) {
+                self.nodes[index].deleted = true;
+                result.deleted.push(id);
+            } else {
+                result.not_found.push(id);
+            }
+        }
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl<E> VectorStore<E> for HnswVectorStore<E>
+where
+    E: Embeddings + Clone + Send + Sync + 'static,
+{
+    fn embeddings(&self) -> Option<Arc<E>> {
+        self.embeddings.clone()
+    }
+
+    async fn add_document(
+        &self,
+        documents: Vec<Document>,
+        ids: Option<Vec<String>>,
+    ) -> anyhow::Result<Vec<String>> {
+        let ids = resolve_ids(ids, documents.len())?;
+        let vectors = self
+            .embeddings
+            .clone()
+            .ok_or(anyhow::anyhow!("embeddings is None"))?
+            .embed_document(&documents)
+            .await?;
+        let dimension = check_embeddings(&vectors, documents.len())?;
+        let mut graph = self.write();
+        if let (Some(dimension), Some(expected)) = (dimension, graph.dimension()) {
+            if dimension != expected {
+                anyhow::bail!(
+                    "embedding dimension mismatch: expected {}, got {}",
+                    expected,
+                    dimension
+                );
+            }
+        }
+        for ((id, document), vector) in ids.iter().zip(documents).zip(vectors) {
+            graph.insert(VectorRecord {
+                id: id.clone(),
+                document,
+                embedding: vector,
+            });
+        }
+        Ok(ids)
+    }
+
+    async fn get_by_ids(&self, ids: &[String]) -> anyhow::Result<Vec<VectorRecord>> {
+        let graph = self.read();
+        Ok(ids
+            .iter()
+            .filter_map(|id| graph.ids.get(id))
+            .map(|index| graph.nodes[*index].record.clone())
+            .collect())
+    }
+
+    async fn similarity_search_records_by_vector(
+        &self,
+        embedding: &[f32],
+        k: Option<usize>,
+        filter: Option<&Filter>,
+        score_threshold: Option<f32>,
+    ) -> anyhow::Result<Vec<(VectorRecord, f32)>> {
+        self.read()
+            .search(embedding, k.unwrap_or(4), filter, score_threshold)
+    }
+
+    async fn delete_document(&self, ids: Vec<String>) -> anyhow::Result<DeleteResult> {
+        Ok(self.write().delete(ids))
+    }
+
+    async fn delete_by_filter(&self, filter: &Filter) -> anyhow::Result<DeleteResult> {
+        let mut graph = self.write();
+        let ids: Vec<String> = graph
+            .nodes
+            .iter()
+            .filter(|node| !node.deleted && filter.matches(&node.record.document.metadata))
+            .map(|node| node.record.id.clone())
+            .collect();
+        Ok(graph.delete(ids))
+    }
+
+    async fn clear(&self) -> anyhow::Result<()> {
+        let mut graph = self.write();
+        graph.nodes.clear();
+        graph.ids.clear();
+        graph.entry_point = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::FakeEmbeddings;
+
+    const DIMENSION: usize = 32;
+
+    fn embeddings() -> FakeEmbeddings {
+        FakeEmbeddings::new(DIMENSION)
+    }
+
+    fn documents(n: usize) -> Vec<Document> {
+        (0..n)
+            .map(|i| Document::new(&format!("doc-{}", i), i).with_metadata("group", i as u64 % 3))
+            .collect()
+    }
+
+    /// 総当たりのInMemoryVectorStoreの結果に対するrecall@k
+    async fn recall(
+        hnsw: &HnswVectorStore<FakeEmbeddings>,
+        exact: &InMemoryVectorStore<FakeEmbeddings>,
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> anyhow::Result<f32> {
+        let mut hits = 0;
+        let mut total = 0;
+        for i in 0..50 {
+            let query = format!("query-{}", i);
+            let expected = exact.similarity_search(&query, Some(k), filter).await?;
+            let actual = hnsw.similarity_search(&query, Some(k), filter).await?;
+            total += expected.len();
+            hits += expected.iter().filter(|doc| actual.contains(doc)).count();
+        }
+        Ok(hits as f32 / total as f32)
+    }
+
+    #[tokio::test]
+    async fn test_recall_against_brute_force() -> anyhow::Result<()> {
+        let hnsw =
+            HnswVectorStore::from_document(documents(500), embeddings(), HnswConfig::default())
+                .await?;
+        let exact = InMemoryVectorStore::from_document(documents(500), embeddings()).await?;
+
+        let recall_at_10 = recall(&hnsw, &exact, 10, None).await?;
+        assert!(recall_at_10 >= 0.95, "recall@10 = {}", recall_at_10);
+
+        let filter = Filter::eq("group", 1);
+        let filtered_recall = recall(&hnsw, &exact, 10, Some(&filter)).await?;
+        assert!(filtered_recall >= 0.9, "recall@10 = {}", filtered_recall);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recall_with_unnormalized_vectors() -> anyhow::Result<()> {
+        // 次元数が大きいので内積は20を超え、MaxInnerProductのスコアは1に飽和する
+        let embeddings = FakeEmbeddings::new(1024);
+        let config = HnswConfig {
+            distance_strategy: DistanceStrategy::MaxInnerProduct,
+            ..Default::default()
+        };
+        let hnsw = HnswVectorStore::from_document(documents(500), embeddings, config).await?;
+        let exact = InMemoryVectorStore::from_document(documents(500), embeddings)
+            .await?
+            .with_distance_strategy(DistanceStrategy::MaxInnerProduct);
+
+        let recall_at_10 = recall(&hnsw, &exact, 10, None).await?;
+        assert!(recall_at_10 >= 0.9, "recall@10 = {}", recall_at_10);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scores_match_brute_force() -> anyhow::Result<()> {
+        let hnsw =
+            HnswVectorStore::from_document(documents(100), embeddings(), HnswConfig::default())
+                .await?;
+        let exact = InMemoryVectorStore::from_document(documents(100), embeddings()).await?;
+        let expected = exact
+            .similarity_search_with_score("query", Some(1), None, None)
+            .await?;
+        let actual = hnsw
+            .similarity_search_with_score("query", Some(1), None, None)
+            .await?;
+        assert_eq!(expected[0].0, actual[0].0);
+        assert!((expected[0].1 - actual[0].1).abs() < 1e-6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incremental_insert_and_delete() -> anyhow::Result<()> {
+        let config = HnswConfig {
+            m: 8,
+            ef_construction: 64,
+            ef_search: 32,
+            ..Default::default()
+        };
+        let hnsw = HnswVectorStore::new(embeddings(), config);
+        let mut ids = Vec::new();
+        for chunk in documents(300).chunks(50) {
+            ids.extend(hnsw.add_document(chunk.to_vec(), None).await?);
+        }
+        assert_eq!(hnsw.len(), 300);
+
+        // 自分自身で検索すれば自分が最も近い
+        let target = Document::new("doc-42", 42).with_metadata("group", 0);
+        let result = hnsw.similarity_search("doc-42", Some(1), None).await?;
+        assert_eq!(result[0], target);
+
+        assert_eq!(
+            hnsw.delete_document(vec![ids[42].clone()]).await?.deleted,
+            vec![ids[42].clone()]
+        );
+        assert_eq!(
+            hnsw.delete_document(vec![ids[42].clone()]).await?.not_found,
+            vec![ids[42].clone()]
+        );
+        assert_eq!(hnsw.len(), 299);
+        let result = hnsw.similarity_search("doc-42", Some(10), None).await?;
+        assert_eq!(result.len(), 10);
+        assert!(!result.contains(&target));
+
+        // 同じidで追加し直すと古いノードは削除済みになる
+        hnsw.add_document(
+            vec![Document::new("doc-42", 42)],
+            Some(vec![ids[43].clone()]),
+        )
+        .await?;
+        assert_eq!(hnsw.len(), 299);
+        let records = hnsw.get_by_ids(&[ids[43].clone(), ids[42].clone()]).await?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].document.page_content, "doc-42");
+
+        hnsw.compact();
+        assert_eq!(hnsw.read().nodes.len(), 299);
+        let result = hnsw.similarity_search("doc-44", Some(1), None).await?;
+        assert_eq!(result[0].page_content, "doc-44");
+
+        let filter = Filter::eq("group", 2);
+        let result = hnsw.delete_by_filter(&filter).await?;
+        assert_eq!(result.deleted.len(), 100);
+        assert_eq!(hnsw.len(), 199);
+        assert!(hnsw
+            .similarity_search("doc-44", Some(10), Some(&filter))
+            .await?
+            .is_empty());
+
+        assert!(hnsw
+            .similarity_search_by_vector(&[1.0, 0.0], Some(1), None)
+            .await
+            .is_err());
+        assert_eq!(
+            hnsw.similarity_search("doc-44", Some(usize::MAX), None)
+                .await?
+                .len(),
+            199
+        );
+
+        hnsw.clear().await?;
+        assert!(hnsw.is_empty());
+        assert!(hnsw
+            .similarity_search("doc-44", None, None)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+}
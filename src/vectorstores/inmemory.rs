@@ -1,15 +1,87 @@
+use super::persist::PersistedStore;
+use super::quantization::{QuantizedQuery, QuantizedVectors};
 use super::*;
-use crate::schema::VectorMetadata;
-use std::collections::HashMap;
-use std::sync::Arc;
-use uuid::Uuid;
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+/// 検索時に1つの並列タスクで処理する行数
+const CHUNK_ROWS: usize = 1024;
+
+/// # InMemoryVectorStore
+///
+/// 全レコードと総当たりで比較するVectorStore
+///
+/// embeddingは1本の`Vec<f32>`に行として連続して並べ、ノルムは追加時に計算しておく。
+/// 検索時はqueryとの内積だけを`CHUNK_ROWS`行ずつ並列に計算し、各タスクは上位`k`件だけをヒープで保持する
+///
+/// `with_quantization`でembeddingを量子化して保持することもできる(`Quantization`を参照)
+///
+/// レコードは`RwLock`の中にあるので、`Arc`に入れたまま複数のタスクから検索・追加・削除できる。
+/// 検索同士はブロックし合わない。追加時はembeddingのAPI呼び出しをロックの外で済ませ、
+/// 1回の`add_document`の分をまとめて1回の書き込みロックで追加する
 pub struct InMemoryVectorStore<E>
 where
     E: Embeddings + Clone + Send + Sync + 'static,
 {
     embeddings: Option<Arc<E>>,
-    documents: HashMap<Uuid, Document<VectorMetadata>>,
+    storage: RwLock<Storage>,
+}
+
+#[derive(Default)]
+struct Storage {
+    rows: Vec<Row>,
+    /// `rows[i]`のembeddingは`vectors[i * dimension..(i + 1) * dimension]`
+    ///
+    /// 量子化して元のベクトルを保持しない場合は空
+    vectors: Vec<f32>,
+    quantized: Option<QuantizedVectors>,
+    dimension: usize,
+    index: HashMap<String, usize>,
+    distance_strategy: DistanceStrategy,
+    quantization: Quantization,
+}
+
+struct Row {
+    id: String,
+    document: Document,
+    norm: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ScoredRow {
+    /// `DistanceStrategy::similarity`の値
+    score: f32,
+    row: usize,
+}
+
+impl Eq for ScoredRow {}
+
+impl PartialOrd for ScoredRow {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScoredRow {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then(other.row.cmp(&self.row))
+    }
+}
+
+/// スコアの小さいものから捨てて、`heap`に上位`k`件だけを残す
+fn push_top_k(heap: &mut BinaryHeap<Reverse<ScoredRow>>, scored: ScoredRow, k: usize) {
+    if heap.len() < k {
+        heap.push(Reverse(scored));
+    } else if heap.peek().is_some_and(|Reverse(min)| scored > *min) {
+        heap.pop();
+        heap.push(Reverse(scored));
+    }
 }
 
 impl<E> InMemoryVectorStore<E>
@@ -19,63 +91,335 @@ where
     pub fn new() -> Self {
         InMemoryVectorStore {
             embeddings: None,
-            documents: HashMap::new(),
+            storage: RwLock::new(Storage::default()),
         }
     }
 
     pub async fn from_document(documents: Vec<Document>, embeddings: E) -> anyhow::Result<Self> {
-        let mut store = InMemoryVectorStore {
+        let store = InMemoryVectorStore {
             embeddings: Some(Arc::new(embeddings)),
-            documents: HashMap::new(),
+            ..Self::new()
         };
-        store.add_document(documents).await?;
+        store.add_document(documents, None).await?;
         Ok(store)
     }
 
-    fn cosine_similarity(&self, a: &Vec<f32>, b: &Vec<f32>) -> f32 {
-        let dot_product = self.dot_product(&a, &b);
-        let a_norm = self.dot_product(&a, &a).sqrt();
-        let b_norm = self.dot_product(&b, &b).sqrt();
-        dot_product / (a_norm * b_norm)
+    /// スコアの計算方法を変更する。デフォルトは`DistanceStrategy::Cosine`
+    pub fn with_distance_strategy(mut self, distance_strategy: DistanceStrategy) -> Self {
+        self.storage_mut().distance_strategy = distance_strategy;
+        self
     }
-    fn dot_product(&self, a: &Vec<f32>, b: &Vec<f32>) -> f32 {
-        // TODO: これサイズが1536とわかっているので、配列にしたほうがいいかも
-        a.iter().zip(b.iter()).map(|(a, b)| a * b).sum()
+
+    /// embeddingの保持方法を変更する。デフォルトは`Quantization::None`
+    ///
+    /// 既に追加されているレコードも変換する。元のベクトルを保持していない状態から戻す場合は、
+    /// 量子化したものから復元した値になる
+    pub fn with_quantization(mut self, quantization: Quantization) -> Self {
+        self.storage_mut().set_quantization(quantization);
+        self
     }
 
-    /// 最も類似度が高いdocumentを返す
-    async fn sort_similarity_documents(
-        &self,
-        docs: Vec<Document<VectorMetadata>>,
-        query: &str,
-    ) -> anyhow::Result<Vec<Document<VectorMetadata>>> {
-        let query_vector = self
+    /// storeの内容をファイルに保存する。形式は`persist`モジュールを参照
+    ///
+    /// 量子化の設定は保存されないので、`load`した後に`with_quantization`で設定し直す
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let embeddings = self
             .embeddings
             .clone()
-            .ok_or(anyhow::anyhow!("embeddings is None"))?
-            .embed_query(query)
-            .await?;
+            .ok_or(anyhow::anyhow!("embeddings is None"))?;
+        let storage = self.read();
+        let records = storage.records();
+        let distance_strategy = storage.distance_strategy;
+        drop(storage);
+        PersistedStore::new(embeddings.as_ref(), records, distance_strategy)?.write(path)
+    }
 
-        let mut doc_vectors: Vec<(Document<VectorMetadata>, f32)> = Vec::new();
+    /// `save`で保存したファイルからstoreを復元する
+    ///
+    /// 保存時とembeddingのモデルや次元数が違う場合はエラーになる
+    pub fn load(path: impl AsRef<Path>, embeddings: E) -> anyhow::Result<Self> {
+        let persisted = PersistedStore::read(path)?;
+        persisted.check_compatible(&embeddings)?;
+        let mut store = InMemoryVectorStore {
+            embeddings: Some(Arc::new(embeddings)),
+            ..Self::new()
+        };
+        let storage = store.storage_mut();
+        storage.distance_strategy = persisted.distance_strategy;
+        for record in persisted.records {
+            storage.insert(record.id, record.document, record.embedding)?;
+        }
+        Ok(store)
+    }
+
+    /// ロック中にpanicしたスレッドがあっても、レコードは常に整合した状態なのでそのまま使う
+    fn read(&self) -> RwLockReadGuard<'_, Storage> {
+        self.storage.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Storage> {
+        self.storage.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn storage_mut(&mut self) -> &mut Storage {
+        self.storage
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
 
-        for doc in docs {
-            let doc_vector = doc
-                .metadata
-                .clone()
-                .ok_or(anyhow::anyhow!("metadata is None"))?
-                .0;
-            let similarity = self.cosine_similarity(&query_vector, &doc_vector);
-            doc_vectors.push((doc, similarity));
+impl Storage {
+    fn set_quantization(&mut self, quantization: Quantization) {
+        let vectors: Vec<Vec<f32>> = (0..self.rows.len())
+            .map(|row| self.vector(row).into_owned())
+            .collect();
+        self.quantization = quantization;
+        self.vectors = Vec::new();
+        self.quantized = QuantizedVectors::new(quantization, self.dimension);
+        for vector in &vectors {
+            self.push_vector(vector);
         }
+    }
 
-        // Sort the documents by similarity in descending order
-        doc_vectors.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    /// レコードがある場合は、次元数が`dimension`と一致しなければエラーになる
+    fn check_dimension(&self, dimension: usize) -> anyhow::Result<()> {
+        if !self.rows.is_empty() && dimension != self.dimension {
+            anyhow::bail!(
+                "embedding dimension mismatch: expected {}, got {}",
+                self.dimension,
+                dimension
+            );
+        }
+        Ok(())
+    }
 
-        // Extract the sorted documents
-        let sorted_docs: Vec<Document<VectorMetadata>> =
-            doc_vectors.into_iter().map(|(doc, _)| doc).collect();
+    /// 既に同じidのレコードがあれば置き換える
+    fn insert(
+        &mut self,
+        id: String,
+        document: Document,
+        embedding: Vec<f32>,
+    ) -> anyhow::Result<()> {
+        self.check_dimension(embedding.len())?;
+        self.remove(&id);
+        if self.rows.is_empty() {
+            self.dimension = embedding.len();
+            self.quantized = QuantizedVectors::new(self.quantization, self.dimension);
+        }
+        self.index.insert(id.clone(), self.rows.len());
+        self.rows.push(Row {
+            id,
+            document,
+            norm: utils::norm(&embedding),
+        });
+        self.push_vector(&embedding);
+        Ok(())
+    }
 
-        Ok(sorted_docs)
+    fn push_vector(&mut self, embedding: &[f32]) {
+        if self.quantization.keeps_originals() {
+            self.vectors.extend_from_slice(embedding);
+        }
+        if let Some(quantized) = &mut self.quantized {
+            quantized.push(embedding);
+        }
+    }
+
+    /// 最後の行を削除する行の位置に移動して詰める
+    fn remove(&mut self, id: &str) -> bool {
+        let Some(row) = self.index.remove(id) else {
+            return false;
+        };
+        let last = self.rows.len() - 1;
+        self.rows.swap_remove(row);
+        if row != last {
+            self.index.insert(self.rows[row].id.clone(), row);
+        }
+        if self.quantization.keeps_originals() {
+            if row != last {
+                self.vectors.copy_within(
+                    last * self.dimension..(last + 1) * self.dimension,
+                    row * self.dimension,
+                );
+            }
+            self.vectors.truncate(last * self.dimension);
+        }
+        if let Some(quantized) = &mut self.quantized {
+            quantized.swap_remove(row);
+        }
+        true
+    }
+
+    /// 元のベクトルを保持していない場合は量子化したものから復元する
+    fn vector(&self, row: usize) -> Cow<'_, [f32]> {
+        match &self.quantized {
+            Some(quantized) if !self.quantization.keeps_originals() => {
+                Cow::Owned(quantized.dequantize(row, self.rows[row].norm))
+            }
+            _ => Cow::Borrowed(&self.vectors[row * self.dimension..(row + 1) * self.dimension]),
+        }
+    }
+
+    /// 順位付けに使う`similarity`を返す。スコア(relevance)への変換は結果を返すときに行う
+    fn exact_score(&self, embedding: &[f32], query_norm: f32, row: usize) -> f32 {
+        let dot = utils::dot_product(
+            embedding,
+            &self.vectors[row * self.dimension..(row + 1) * self.dimension],
+        );
+        self.distance_strategy
+            .similarity_from_dot(dot, query_norm, self.rows[row].norm)
+    }
+
+    fn quantized_score(
+        &self,
+        quantized: &QuantizedVectors,
+        query: &QuantizedQuery,
+        query_norm: f32,
+        row: usize,
+    ) -> f32 {
+        let norm = self.rows[row].norm;
+        let dot = quantized.estimate_dot(query, query_norm, row, norm);
+        self.distance_strategy
+            .similarity_from_dot(dot, query_norm, norm)
+    }
+
+    /// filterにマッチする行を`CHUNK_ROWS`行ずつ並列に`score`で採点し、スコアの高い順に最大`k`件返す
+    ///
+    /// `score`は`similarity`で、`score_threshold`はスコア(relevance)に変換したものと比べる
+    fn top_k(
+        &self,
+        k: usize,
+        filter: Option<&Filter>,
+        score_threshold: Option<f32>,
+        score: impl Fn(usize) -> f32 + Sync,
+    ) -> Vec<ScoredRow> {
+        let top_k = self
+            .rows
+            .par_chunks(CHUNK_ROWS)
+            .enumerate()
+            .map(|(chunk, rows)| {
+                // 1つのタスクが保持するのはそのchunkの行数まで
+                let mut heap = BinaryHeap::with_capacity(k.min(rows.len()) + 1);
+                for (offset, row) in rows.iter().enumerate() {
+                    // filterにマッチしないレコードはスコアを計算する前に除外する
+                    if !filter.is_none_or(|f| f.matches(&row.document.metadata)) {
+                        continue;
+                    }
+                    let index = chunk * CHUNK_ROWS + offset;
+                    let score = score(index);
+                    if self.passes(score, score_threshold) {
+                        push_top_k(&mut heap, ScoredRow { score, row: index }, k);
+                    }
+                }
+                heap
+            })
+            .reduce(BinaryHeap::new, |mut merged, heap| {
+                for Reverse(scored) in heap {
+                    push_top_k(&mut merged, scored, k);
+                }
+                merged
+            });
+        // into_sorted_vecはReverseの昇順、つまりスコアの降順になる
+        top_k
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(scored)| scored)
+            .collect()
+    }
+
+    fn passes(&self, similarity: f32, score_threshold: Option<f32>) -> bool {
+        score_threshold.is_none_or(|threshold| {
+            self.distance_strategy.relevance_from_similarity(similarity) >= threshold
+        })
+    }
+
+    fn record(&self, row: usize) -> VectorRecord {
+        VectorRecord {
+            id: self.rows[row].id.clone(),
+            document: self.rows[row].document.clone(),
+            embedding: self.vector(row).into_owned(),
+        }
+    }
+
+    fn records(&self) -> Vec<VectorRecord> {
+        (0..self.rows.len()).map(|row| self.record(row)).collect()
+    }
+
+    /// `distance_strategy`で全レコードを総当たりで比較する
+    fn search(
+        &self,
+        embedding: &[f32],
+        k: usize,
+        filter: Option<&Filter>,
+        score_threshold: Option<f32>,
+    ) -> anyhow::Result<Vec<(VectorRecord, f32)>> {
+        // 行数より多くは返せないので、以降の計算であふれないよう先に丸める
+        let k = k.min(self.rows.len());
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+        if embedding.len() != self.dimension {
+            anyhow::bail!(
+                "query dimension mismatch: expected {}, got {}",
+                self.dimension,
+                embedding.len()
+            );
+        }
+        let query_norm = utils::norm(embedding);
+
+        let top_k = match &self.quantized {
+            None => self.top_k(k, filter, score_threshold, |row| {
+                self.exact_score(embedding, query_norm, row)
+            }),
+            Some(quantized) => {
+                let query = quantized.query(embedding);
+                let score = |row| self.quantized_score(quantized, &query, query_norm, row);
+                if self.quantization.keeps_originals() {
+                    // 量子化したベクトルで多めに候補を集め、元のベクトルでスコアを計算し直す
+                    let candidates = self.top_k(
+                        k.saturating_mul(self.quantization.oversampling()),
+                        filter,
+                        None,
+                        score,
+                    );
+                    let mut rescored: Vec<ScoredRow> = candidates
+                        .into_iter()
+                        .map(|candidate| ScoredRow {
+                            score: self.exact_score(embedding, query_norm, candidate.row),
+                            row: candidate.row,
+                        })
+                        .filter(|scored| self.passes(scored.score, score_threshold))
+                        .collect();
+                    rescored.sort_by(|a, b| b.cmp(a));
+                    rescored.truncate(k);
+                    rescored
+                } else {
+                    self.top_k(k, filter, score_threshold, score)
+                }
+            }
+        };
+
+        Ok(top_k
+            .into_iter()
+            .map(|scored| {
+                let score = self
+                    .distance_strategy
+                    .relevance_from_similarity(scored.score);
+                (self.record(scored.row), score)
+            })
+            .collect())
+    }
+
+    fn delete(&mut self, ids: Vec<String>) -> DeleteResult {
+        let mut result = DeleteResult::default();
+        for id in ids {
+            if self.remove(&id) {
+                result.deleted.push(id);
+            } else {
+                result.not_found.push(id);
+            }
+        }
+        result
     }
 }
 
@@ -88,48 +432,678 @@ where
         self.embeddings.clone()
     }
 
-    async fn add_document(&mut self, documents: Vec<Document>) -> anyhow::Result<Vec<String>> {
-        let mut ids = Vec::new();
+    async fn add_document(
+        &self,
+        documents: Vec<Document>,
+        ids: Option<Vec<String>>,
+    ) -> anyhow::Result<Vec<String>> {
+        let ids = resolve_ids(ids, documents.len())?;
         let vectors = self
             .embeddings
             .clone()
             .ok_or(anyhow::anyhow!("embeddings is None"))?
             .embed_document(&documents)
             .await?;
-        for (index, document) in documents.iter().enumerate() {
-            let document: Document<VectorMetadata> = Document {
-                page_content: document.page_content.clone(),
-                lookup_str: document.lookup_str.clone(),
-                lookup_index: document.lookup_index,
-                metadata: Some(vectors[index].clone().into()),
-            };
-            let id = Uuid::new_v4();
-            self.documents.insert(id.clone(), document);
-            ids.push(id);
+        let dimension = check_embeddings(&vectors, documents.len())?;
+        let mut storage = self.write();
+        if let Some(dimension) = dimension {
+            storage.check_dimension(dimension)?;
+        }
+        for ((id, document), vector) in ids.iter().zip(documents).zip(vectors) {
+            storage.insert(id.clone(), document, vector)?;
         }
-        Ok(ids.iter().map(|id| id.to_string()).collect())
+        Ok(ids)
+    }
+
+    async fn get_by_ids(&self, ids: &[String]) -> anyhow::Result<Vec<VectorRecord>> {
+        let storage = self.read();
+        Ok(ids
+            .iter()
+            .filter_map(|id| storage.index.get(id))
+            .map(|row| storage.record(*row))
+            .collect())
     }
 
-    async fn similarity_search(
+    async fn similarity_search_records_by_vector(
         &self,
-        query: &str,
+        embedding: &[f32],
         k: Option<usize>,
-    ) -> anyhow::Result<Vec<Document<VectorMetadata>>> {
-        let k = k.unwrap_or(4);
-        let docs: Vec<Document<VectorMetadata>> = self.documents.values().cloned().collect();
-        let docs = self.sort_similarity_documents(docs, query).await?;
-        Ok(docs.into_iter().take(k).collect())
+        filter: Option<&Filter>,
+        score_threshold: Option<f32>,
+    ) -> anyhow::Result<Vec<(VectorRecord, f32)>> {
+        self.read()
+            .search(embedding, k.unwrap_or(4), filter, score_threshold)
     }
 
-    fn delete_document(&mut self, ids: Vec<String>) -> anyhow::Result<bool> {
-        let mut deleted = false;
-        for id in ids {
-            if let Ok(id) = Uuid::parse_str(&id) {
-                if self.documents.remove(&id).is_some() {
-                    deleted = true;
-                }
+    async fn delete_document(&self, ids: Vec<String>) -> anyhow::Result<DeleteResult> {
+        Ok(self.write().delete(ids))
+    }
+
+    async fn delete_by_filter(&self, filter: &Filter) -> anyhow::Result<DeleteResult> {
+        let mut storage = self.write();
+        let ids: Vec<String> = storage
+            .rows
+            .iter()
+            .filter(|row| filter.matches(&row.document.metadata))
+            .map(|row| row.id.clone())
+            .collect();
+        Ok(storage.delete(ids))
+    }
+
+    async fn clear(&self) -> anyhow::Result<()> {
+        let mut storage = self.write();
+        *storage = Storage {
+            distance_strategy: storage.distance_strategy,
+            quantization: storage.quantization,
+            ..Storage::default()
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::{FakeEmbeddings, HashingEmbeddings};
+
+    /// 'a', 'b', 'c'の出現回数をそのままベクトルにするだけのテスト用Embeddings
+    #[derive(Clone)]
+    struct CharCountEmbeddings;
+
+    fn char_counts(text: &str) -> Vec<f32> {
+        ['a', 'b', 'c']
+            .iter()
+            .map(|c| text.chars().filter(|t| t == c).count() as f32)
+            .collect()
+    }
+
+    #[async_trait::async_trait]
+    impl Embeddings for CharCountEmbeddings {
+        async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+            Ok(texts.iter().map(|text| char_counts(text)).collect())
+        }
+
+        fn model_name(&self) -> &str {
+            "char-count"
+        }
+
+        fn dimension(&self) -> Option<usize> {
+            Some(3)
+        }
+    }
+
+    fn temp_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("langchain-test-{}.json", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn test_similarity_search_keeps_metadata() -> anyhow::Result<()> {
+        let docs = vec![
+            Document::new("aaa", 0).with_metadata("source", "a.txt"),
+            Document::new("bbb", 1).with_metadata("source", "b.txt"),
+        ];
+        let store = InMemoryVectorStore::from_document(docs, CharCountEmbeddings).await?;
+        let result = store.similarity_search("bb", Some(1), None).await?;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].page_content, "bbb");
+        assert_eq!(result[0].metadata["source"], "b.txt");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_similarity_search_with_vector() -> anyhow::Result<()> {
+        let docs = vec![Document::new("aaa", 0), Document::new("abc", 1)];
+        let store = InMemoryVectorStore::from_document(vec![], CharCountEmbeddings).await?;
+        let ids = store.add_document(docs, None).await?;
+        let records = store
+            .similarity_search_with_vector("c", Some(1), None)
+            .await?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, ids[1]);
+        assert_eq!(records[0].document.page_content, "abc");
+        assert_eq!(records[0].embedding, vec![1.0, 1.0, 1.0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_similarity_search_with_score() -> anyhow::Result<()> {
+        let docs = vec![
+            Document::new("aaa", 0),
+            Document::new("aab", 1),
+            Document::new("ccc", 2),
+        ];
+        let store = InMemoryVectorStore::from_document(docs, CharCountEmbeddings).await?;
+
+        let result = store
+            .similarity_search_with_score("a", Some(3), None, None)
+            .await?;
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[0].0.page_content, "aaa");
+        assert!((result[0].1 - 1.0).abs() < 1e-6);
+        assert!(result[0].1 >= result[1].1 && result[1].1 >= result[2].1);
+
+        // "ccc"とのcosine similarityは0(スコアは0.5)なので閾値で落とされる
+        let result = store
+            .similarity_search_with_score("a", Some(3), None, Some(0.75))
+            .await?;
+        assert_eq!(result.len(), 2);
+        assert!(result.iter().all(|(doc, _)| doc.page_content != "ccc"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_similarity_search_with_filter() -> anyhow::Result<()> {
+        let docs = vec![
+            Document::new("aaa", 0).with_metadata("channel", "C1"),
+            Document::new("aab", 1).with_metadata("channel", "C2"),
+            Document::new("abb", 2).with_metadata("channel", "C2"),
+        ];
+        let store = InMemoryVectorStore::from_document(docs, CharCountEmbeddings).await?;
+
+        let filter = Filter::eq("channel", "C2");
+        let result = store.similarity_search("a", Some(4), Some(&filter)).await?;
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].page_content, "aab");
+        assert!(result.iter().all(|doc| doc.metadata["channel"] == "C2"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_marginal_relevance_search() -> anyhow::Result<()> {
+        let docs = vec![
+            Document::new("aaaab", 0),
+            Document::new("aaaab", 1),
+            Document::new("bbc", 2),
+        ];
+        let store = InMemoryVectorStore::from_document(docs, CharCountEmbeddings).await?;
+
+        let result = store.similarity_search("ab", Some(2), None).await?;
+        assert!(result.iter().all(|doc| doc.page_content == "aaaab"));
+
+        let result = store
+            .max_marginal_relevance_search("ab", Some(2), None, None, None)
+            .await?;
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].page_content, "aaaab");
+        assert_eq!(result[1].page_content, "bbc");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_similarity_search_by_vector() -> anyhow::Result<()> {
+        let docs = vec![Document::new("aaa", 0), Document::new("bbb", 1)];
+        let store = InMemoryVectorStore::from_document(docs, CharCountEmbeddings).await?;
+
+        let result = store
+            .similarity_search_with_score_by_vector(&[0.0, 1.0, 0.0], Some(1), None, None)
+            .await?;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].0.page_content, "bbb");
+
+        // テキストで検索した場合と結果が一致する
+        let by_text = store.similarity_search("b", Some(2), None).await?;
+        let by_vector = store
+            .similarity_search_by_vector(&[0.0, 1.0, 0.0], Some(2), None)
+            .await?;
+        assert_eq!(by_text, by_vector);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() -> anyhow::Result<()> {
+        let docs = vec![
+            Document::new("aaa", 0).with_metadata("source", "a.txt"),
+            Document::new("bbb", 1).with_metadata("source", "b.txt"),
+        ];
+        let store = InMemoryVectorStore::from_document(docs, CharCountEmbeddings)
+            .await?
+            .with_distance_strategy(DistanceStrategy::MaxInnerProduct);
+        let path = temp_path();
+        store.save(&path)?;
+
+        let loaded = InMemoryVectorStore::load(&path, CharCountEmbeddings)?;
+        assert_eq!(
+            loaded.read().distance_strategy,
+            DistanceStrategy::MaxInnerProduct
+        );
+
+        // distance_strategyが無い古いファイルはCosineとして読む
+        let mut json: serde_json::Value = serde_json::from_slice(&std::fs::read(&path)?)?;
+        json.as_object_mut().unwrap().remove("distance_strategy");
+        std::fs::write(&path, serde_json::to_vec(&json)?)?;
+        let old = InMemoryVectorStore::load(&path, CharCountEmbeddings)?;
+        assert_eq!(old.read().distance_strategy, DistanceStrategy::Cosine);
+        std::fs::remove_file(&path)?;
+        let sorted = |mut records: Vec<VectorRecord>| {
+            records.sort_by(|a, b| a.id.cmp(&b.id));
+            records
+        };
+        assert_eq!(
+            sorted(loaded.read().records()),
+            sorted(store.read().records())
+        );
+        let result = loaded.similarity_search("b", Some(1), None).await?;
+        assert_eq!(result[0].metadata["source"], "b.txt");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_rejects_incompatible_embeddings() -> anyhow::Result<()> {
+        let store =
+            InMemoryVectorStore::from_document(vec![Document::new("a", 0)], CharCountEmbeddings)
+                .await?;
+        let path = temp_path();
+        store.save(&path)?;
+
+        let mut persisted = PersistedStore::read(&path)?;
+        persisted.model = "other-model".to_string();
+        persisted.write(&path)?;
+        assert!(InMemoryVectorStore::load(&path, CharCountEmbeddings).is_err());
+
+        persisted.model = "char-count".to_string();
+        persisted.dimension = Some(4);
+        persisted.records.clear();
+        persisted.write(&path)?;
+        assert!(InMemoryVectorStore::load(&path, CharCountEmbeddings).is_err());
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_distance_strategy() -> anyhow::Result<()> {
+        let docs = vec![Document::new("a", 0), Document::new("aaaa", 1)];
+        let store = InMemoryVectorStore::from_document(docs, CharCountEmbeddings).await?;
+        let query = [2.0, 0.0, 0.0];
+
+        // cosineではノルムを無視するので同点になる
+        let result = store
+            .similarity_search_with_score_by_vector(&query, Some(2), None, None)
+            .await?;
+        assert_eq!(result[0].1, result[1].1);
+
+        let store = store.with_distance_strategy(DistanceStrategy::EuclideanDistance);
+        let result = store
+            .similarity_search_with_score_by_vector(&query, Some(2), None, None)
+            .await?;
+        assert_eq!(result[0].0.page_content, "a");
+        assert_eq!(result[0].1, 0.5);
+
+        let store = store.with_distance_strategy(DistanceStrategy::MaxInnerProduct);
+        let result = store
+            .similarity_search_with_score_by_vector(&query, Some(2), None, None)
+            .await?;
+        assert_eq!(result[0].0.page_content, "aaaa");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inner_product_ranking_with_large_dot_products() -> anyhow::Result<()> {
+        // queryとの内積は20, 25, 30。スコアは飽和するが、順位は内積の大きい順になる
+        let docs = vec![
+            Document::new("aaaa", 0),
+            Document::new("aaaaab", 1),
+            Document::new("aaaaaa", 2),
+        ];
+        let query = [5.0, 0.0, 0.0];
+        for strategy in [
+            DistanceStrategy::DotProduct,
+            DistanceStrategy::MaxInnerProduct,
+        ] {
+            let store = InMemoryVectorStore::from_document(docs.clone(), CharCountEmbeddings)
+                .await?
+                .with_distance_strategy(strategy);
+            let result = store
+                .similarity_search_with_score_by_vector(&query, Some(3), None, None)
+                .await?;
+            let contents: Vec<&str> = result
+                .iter()
+                .map(|(doc, _)| doc.page_content.as_str())
+                .collect();
+            assert_eq!(contents, vec!["aaaaaa", "aaaaab", "aaaa"], "{:?}", strategy);
+            assert!(result.iter().all(|(_, score)| *score == 1.0));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_search_across_chunks_after_delete() -> anyhow::Result<()> {
+        // CHUNK_ROWSをまたぐ件数で、並列に計算した上位k件のマージと削除後の行の詰め直しを確認する
+        let docs: Vec<Document> = (0..CHUNK_ROWS * 2 + 10)
+            .map(|i| Document::new(&"a".repeat(i % 50 + 1), i))
+            .collect();
+        let store = InMemoryVectorStore::from_document(vec![], CharCountEmbeddings).await?;
+        let ids = store.add_document(docs, None).await?;
+        let query = [2.0, 0.0, 0.0];
+        let store = store.with_distance_strategy(DistanceStrategy::EuclideanDistance);
+
+        let result = store
+            .similarity_search_with_score_by_vector(&query, Some(3), None, None)
+            .await?;
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[0].0.page_content, "aa");
+        assert!(result.windows(2).all(|w| w[0].1 >= w[1].1));
+
+        let deleted: Vec<String> = ids
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 50 == 1)
+            .map(|(_, id)| id.clone())
+            .collect();
+        let result = store.delete_document(deleted.clone()).await?;
+        assert_eq!(result.deleted, deleted);
+        assert!(result.not_found.is_empty());
+        let result = store
+            .similarity_search_with_score_by_vector(&query, Some(1), None, None)
+            .await?;
+        assert_ne!(result[0].0.page_content, "aa");
+        let storage = store.read();
+        for (row, record) in storage.records().iter().enumerate() {
+            assert_eq!(storage.index[&record.id], row);
+            assert_eq!(record.embedding, char_counts(&record.document.page_content));
+        }
+        Ok(())
+    }
+
+    /// `FakeEmbeddings`のベクトルを直接入れたstoreを作る
+    fn random_store(n: usize, dimension: usize) -> InMemoryVectorStore<CharCountEmbeddings> {
+        let fake = FakeEmbeddings::new(dimension);
+        let mut store = InMemoryVectorStore::new();
+        for i in 0..n {
+            let document = Document::new(&format!("doc-{}", i), i).with_metadata("group", i % 3);
+            let vector = fake.embed_one(&document.page_content);
+            store
+                .storage_mut()
+                .insert(format!("id-{}", i), document, vector)
+                .unwrap();
+        }
+        store
+    }
+
+    /// 量子化しない総当たりの結果に対するrecall@10。queryには保存されているベクトル自身を使う
+    async fn recall(
+        exact: &InMemoryVectorStore<CharCountEmbeddings>,
+        quantized: &InMemoryVectorStore<CharCountEmbeddings>,
+        filter: Option<&Filter>,
+    ) -> anyhow::Result<f32> {
+        let mut hits = 0;
+        let mut total = 0;
+        let rows = exact.read().rows.len();
+        for row in (0..rows).step_by(20) {
+            let query = exact.read().vector(row).into_owned();
+            let expected = exact
+                .similarity_search_by_vector(&query, Some(10), filter)
+                .await?;
+            let actual = quantized
+                .similarity_search_by_vector(&query, Some(10), filter)
+                .await?;
+            total += expected.len();
+            hits += expected.iter().filter(|doc| actual.contains(doc)).count();
+        }
+        Ok(hits as f32 / total as f32)
+    }
+
+    #[tokio::test]
+    async fn test_quantization_recall() -> anyhow::Result<()> {
+        let exact = random_store(1000, 256);
+        let filter = Filter::eq("group", 1);
+        // (量子化の方法, filterなしのrecallの下限, filterありのrecallの下限)
+        //
+        // 一様乱数のベクトルは近傍との差が小さく量子化には厳しいデータで、
+        // 実測値はそれぞれ 1.0/1.0, 0.99/0.99, 0.83/0.94, 0.36/0.42 だった
+        let cases = [
+            (Quantization::Int8 { rescore: true }, 1.0, 1.0),
+            (Quantization::Int8 { rescore: false }, 0.97, 0.97),
+            (Quantization::Binary { rescore: true }, 0.75, 0.9),
+            (Quantization::Binary { rescore: false }, 0.3, 0.35),
+        ];
+        for (quantization, expected, expected_filtered) in cases {
+            let quantized = random_store(1000, 256).with_quantization(quantization);
+            let recall_at_10 = recall(&exact, &quantized, None).await?;
+            assert!(
+                recall_at_10 >= expected,
+                "{:?}: recall@10 = {}",
+                quantization,
+                recall_at_10
+            );
+            let filtered_recall = recall(&exact, &quantized, Some(&filter)).await?;
+            assert!(
+                filtered_recall >= expected_filtered,
+                "{:?}: recall@10 = {}",
+                quantization,
+                filtered_recall
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_search_with_huge_k() -> anyhow::Result<()> {
+        let query = random_store(1, 16).read().vector(0).into_owned();
+        for quantization in [Quantization::None, Quantization::Int8 { rescore: true }] {
+            let store = random_store(30, 16).with_quantization(quantization);
+            for k in [usize::MAX, usize::MAX / 2] {
+                let result = store
+                    .similarity_search_by_vector(&query, Some(k), None)
+                    .await?;
+                assert_eq!(result.len(), 30, "{:?}", quantization);
             }
         }
-        Ok(deleted)
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_quantization_rescore() -> anyhow::Result<()> {
+        let exact = random_store(200, 64);
+        let query = exact.read().vector(7).into_owned();
+        let expected = exact
+            .similarity_search_records_by_vector(&query, Some(5), None, None)
+            .await?;
+
+        // rescoreした場合はスコアもembeddingも量子化しない場合と一致する
+        let rescored =
+            random_store(200, 64).with_quantization(Quantization::Int8 { rescore: true });
+        let actual = rescored
+            .similarity_search_records_by_vector(&query, Some(5), None, None)
+            .await?;
+        assert_eq!(actual, expected);
+
+        // rescoreしない場合は元のベクトルを捨て、スコアは近似になる
+        let approximate =
+            random_store(200, 64).with_quantization(Quantization::Int8 { rescore: false });
+        assert!(approximate.read().vectors.is_empty());
+        let actual = approximate
+            .similarity_search_records_by_vector(&query, Some(1), None, Some(0.9))
+            .await?;
+        assert_eq!(actual[0].0.document, expected[0].0.document);
+        assert!((actual[0].1 - expected[0].1).abs() < 1e-3);
+        assert_ne!(actual[0].0.embedding, expected[0].0.embedding);
+
+        // 削除しても量子化したベクトルの行がずれない
+        let id = expected[0].0.id.clone();
+        assert_eq!(
+            approximate.delete_document(vec![id]).await?.deleted.len(),
+            1
+        );
+        let actual = approximate
+            .similarity_search_records_by_vector(&query, Some(1), None, None)
+            .await?;
+        assert_eq!(actual[0].0.document, expected[1].0.document);
+
+        let restored = approximate.with_quantization(Quantization::None);
+        assert_eq!(restored.read().vectors.len(), 199 * 64);
+        Ok(())
+    }
+
+    /// テキストの長さを次元数とするembeddingを返す。空のテキストのembeddingは返さない
+    #[derive(Clone)]
+    struct RaggedEmbeddings;
+
+    #[async_trait::async_trait]
+    impl Embeddings for RaggedEmbeddings {
+        async fn embed_texts(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+            Ok(texts
+                .iter()
+                .filter(|text| !text.is_empty())
+                .map(|text| vec![1.0; text.len()])
+                .collect())
+        }
+
+        fn model_name(&self) -> &str {
+            "ragged"
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_document_is_all_or_nothing() -> anyhow::Result<()> {
+        let docs = |texts: &[&str]| -> Vec<Document> {
+            texts
+                .iter()
+                .enumerate()
+                .map(|(i, text)| Document::new(text, i))
+                .collect()
+        };
+        let store = InMemoryVectorStore::from_document(docs(&["abc"]), RaggedEmbeddings).await?;
+
+        // embeddingの数が足りない
+        assert!(store.add_document(docs(&["xyz", ""]), None).await.is_err());
+        // 1件目は追加できるが、2件目の次元数が違う
+        assert!(store
+            .add_document(docs(&["xyz", "ab"]), None)
+            .await
+            .is_err());
+        // storeの次元数と違う
+        assert!(store.add_document(docs(&["ab", "cd"]), None).await.is_err());
+        assert_eq!(store.read().rows.len(), 1);
+
+        store.add_document(docs(&["xyz"]), None).await?;
+        assert_eq!(store.read().rows.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_document_with_ids() -> anyhow::Result<()> {
+        let store = InMemoryVectorStore::from_document(vec![], CharCountEmbeddings).await?;
+        let ids = vec!["page-1".to_string(), "page-2".to_string()];
+        let added = store
+            .add_document(
+                vec![Document::new("aaa", 0), Document::new("bbb", 1)],
+                Some(ids.clone()),
+            )
+            .await?;
+        assert_eq!(added, ids);
+
+        // 同じidで追加し直すと置き換わり、件数は増えない
+        store
+            .add_document(
+                vec![Document::new("ccc", 0)],
+                Some(vec!["page-1".to_string()]),
+            )
+            .await?;
+        assert_eq!(store.read().rows.len(), 2);
+        let records = store
+            .get_by_ids(&[
+                "page-1".to_string(),
+                "missing".to_string(),
+                "page-2".to_string(),
+            ])
+            .await?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id, "page-1");
+        assert_eq!(records[0].document.page_content, "ccc");
+        assert_eq!(records[0].embedding, vec![0.0, 0.0, 3.0]);
+        assert_eq!(records[1].document.page_content, "bbb");
+        let result = store.similarity_search("a", Some(2), None).await?;
+        assert!(result.iter().all(|doc| doc.page_content != "aaa"));
+
+        // idの数がdocumentの数と違う場合はエラー
+        assert!(store
+            .add_document(vec![Document::new("a", 0)], Some(vec![]))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_by_filter_and_clear() -> anyhow::Result<()> {
+        let docs = vec![
+            Document::new("aaa", 0).with_metadata("source", "slack://C1/1"),
+            Document::new("aab", 1).with_metadata("source", "slack://C1/1"),
+            Document::new("abb", 2).with_metadata("source", "slack://C1/2"),
+        ];
+        let store = InMemoryVectorStore::from_document(vec![], CharCountEmbeddings).await?;
+        let ids = store.add_document(docs, None).await?;
+
+        let result = store
+            .delete_by_filter(&Filter::eq("source", "slack://C1/1"))
+            .await?;
+        assert_eq!(result.deleted, ids[..2].to_vec());
+        assert!(result.not_found.is_empty());
+        let result = store.similarity_search("a", Some(4), None).await?;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].page_content, "abb");
+
+        let result = store
+            .delete_document(vec![ids[0].clone(), ids[2].clone()])
+            .await?;
+        assert_eq!(result.deleted, vec![ids[2].clone()]);
+        assert_eq!(result.not_found, vec![ids[0].clone()]);
+
+        store
+            .add_document(vec![Document::new("ccc", 0)], None)
+            .await?;
+        store.clear().await?;
+        assert!(store.read().rows.is_empty() && store.read().vectors.is_empty());
+        assert!(store.similarity_search("c", None, None).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_add_and_search() -> anyhow::Result<()> {
+        // Mutexで包まずにArcだけで複数のタスクから同時に使える
+        let store =
+            Arc::new(InMemoryVectorStore::from_document(vec![], FakeEmbeddings::new(8)).await?);
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    let docs = (0..50)
+                        .map(|i| Document::new(&"a".repeat(i + 1), i))
+                        .collect();
+                    let ids = store.add_document(docs, None).await?;
+                    let result = store.similarity_search("a", Some(4), None).await?;
+                    assert_eq!(result.len(), 4);
+                    store.delete_document(ids[..10].to_vec()).await?;
+                    anyhow::Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await??;
+        }
+        assert_eq!(store.read().rows.len(), 8 * 40);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_similarity_search_with_hashing_embeddings() -> anyhow::Result<()> {
+        let docs = vec![
+            Document::new("Bake the bread at 220 degrees for 40 minutes.", 0),
+            Document::new(
+                "Rust guarantees memory safety without a garbage collector.",
+                1,
+            ),
+            Document::new("Slack channels can be archived by workspace admins.", 2),
+        ];
+        let store = InMemoryVectorStore::from_document(docs, HashingEmbeddings::new(256)).await?;
+        let result = store
+            .similarity_search("How long should I bake bread?", Some(1), None)
+            .await?;
+        assert_eq!(result[0].lookup_index, 0);
+        let result = store
+            .similarity_search("Who can archive a Slack channel?", Some(1), None)
+            .await?;
+        assert_eq!(result[0].lookup_index, 2);
+        Ok(())
     }
 }
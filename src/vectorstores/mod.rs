@@ -1,22 +1,261 @@
+pub mod filter;
+pub mod hnsw;
 pub mod inmemory;
+pub mod persist;
+pub mod quantization;
+pub mod utils;
 
+pub use filter::*;
+pub use hnsw::*;
 pub use inmemory::*;
+pub use quantization::Quantization;
+pub use utils::DistanceStrategy;
 
 use crate::embeddings::Embeddings;
-use crate::schema::{Document, VectorMetadata};
+use crate::schema::Document;
 use std::sync::Arc;
 
+/// VectorStoreが内部で保持する1件分のレコード
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct VectorRecord {
+    pub id: String,
+    pub document: Document,
+    pub embedding: Vec<f32>,
+}
+
+/// `VectorStore`の削除メソッドの結果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeleteResult {
+    /// 削除したレコードのid
+    pub deleted: Vec<String>,
+    /// 指定されたが存在しなかったid
+    pub not_found: Vec<String>,
+}
+
+/// # VectorStore
+///
+/// 実装が必要な検索メソッドは`similarity_search_records_by_vector`だけで、
+/// それ以外の検索メソッドはすべてこれを使ったデフォルト実装になっている
+///
+/// queryを受け取るメソッドは`Embeddings::embed_query`でベクトルにしてから`*_by_vector`を呼ぶだけなので、
+/// 既にqueryのベクトルを持っている場合は`*_by_vector`を直接呼べばembeddingのAPI呼び出しを省ける
+///
+/// すべてのメソッドが`&self`を取るので、実装は内部で同期する必要がある。
+/// `Arc`に入れれば複数のタスクから同時に検索・追加・削除できる
 #[async_trait::async_trait]
-pub trait VectorStore<E>
+pub trait VectorStore<E>: Send + Sync
 where
     E: Embeddings + Clone + Send + Sync + 'static,
 {
     fn embeddings(&self) -> Option<Arc<E>>;
+
+    /// 類似度の高い順にレコードとそのスコアを返す
+    ///
+    /// スコアは0から1の範囲で、大きいほど似ていることを表す(`DistanceStrategy`を参照)
+    ///
+    /// `filter`を指定した場合、metadataがマッチするレコードだけを対象にする
+    ///
+    /// `score_threshold`を指定した場合、スコアがそれ未満のレコードは返さない
+    async fn similarity_search_records_by_vector(
+        &self,
+        embedding: &[f32],
+        k: Option<usize>,
+        filter: Option<&Filter>,
+        score_threshold: Option<f32>,
+    ) -> anyhow::Result<Vec<(VectorRecord, f32)>>;
+
+    async fn similarity_search_by_vector(
+        &self,
+        embedding: &[f32],
+        k: Option<usize>,
+        filter: Option<&Filter>,
+    ) -> anyhow::Result<Vec<Document>> {
+        let records = self
+            .similarity_search_records_by_vector(embedding, k, filter, None)
+            .await?;
+        Ok(records
+            .into_iter()
+            .map(|(record, _)| record.document)
+            .collect())
+    }
+
+    async fn similarity_search_with_score_by_vector(
+        &self,
+        embedding: &[f32],
+        k: Option<usize>,
+        filter: Option<&Filter>,
+        score_threshold: Option<f32>,
+    ) -> anyhow::Result<Vec<(Document, f32)>> {
+        let records = self
+            .similarity_search_records_by_vector(embedding, k, filter, score_threshold)
+            .await?;
+        Ok(records
+            .into_iter()
+            .map(|(record, score)| (record.document, score))
+            .collect())
+    }
+
+    /// Maximal Marginal Relevanceで、`embedding`に近くかつ互いに似すぎないdocumentを返す
+    ///
+    /// 類似度の高い`fetch_k`件(デフォルト20)を候補とし、そこから`k`件(デフォルト4)を選ぶ
+    ///
+    /// `lambda_mult`(デフォルト0.5)は1に近いほど類似度を、0に近いほど多様性を重視する
+    async fn max_marginal_relevance_search_by_vector(
+        &self,
+        embedding: &[f32],
+        k: Option<usize>,
+        fetch_k: Option<usize>,
+        lambda_mult: Option<f32>,
+        filter: Option<&Filter>,
+    ) -> anyhow::Result<Vec<Document>> {
+        let candidates = self
+            .similarity_search_records_by_vector(
+                embedding,
+                Some(fetch_k.unwrap_or(20)),
+                filter,
+                None,
+            )
+            .await?;
+        let candidate_vectors: Vec<&[f32]> = candidates
+            .iter()
+            .map(|(record, _)| record.embedding.as_slice())
+            .collect();
+        let selected = utils::maximal_marginal_relevance(
+            embedding,
+            &candidate_vectors,
+            lambda_mult.unwrap_or(0.5),
+            k.unwrap_or(4),
+        );
+        Ok(selected
+            .into_iter()
+            .map(|index| candidates[index].0.document.clone())
+            .collect())
+    }
+
     async fn similarity_search(
         &self,
         query: &str,
         k: Option<usize>,
-    ) -> anyhow::Result<Vec<Document<VectorMetadata>>>;
-    async fn add_document(&mut self, documents: Vec<Document>) -> anyhow::Result<Vec<String>>;
-    fn delete_document(&mut self, ids: Vec<String>) -> anyhow::Result<bool>;
+        filter: Option<&Filter>,
+    ) -> anyhow::Result<Vec<Document>> {
+        let embedding = embed_query(self.embeddings(), query).await?;
+        self.similarity_search_by_vector(&embedding, k, filter)
+            .await
+    }
+
+    /// 類似度の高い順にdocumentとそのスコアを返す
+    ///
+    /// `filter`を指定した場合、metadataがマッチするdocumentだけを対象にする
+    ///
+    /// `score_threshold`を指定した場合、スコアがそれ未満のdocumentは返さない
+    async fn similarity_search_with_score(
+        &self,
+        query: &str,
+        k: Option<usize>,
+        filter: Option<&Filter>,
+        score_threshold: Option<f32>,
+    ) -> anyhow::Result<Vec<(Document, f32)>> {
+        let embedding = embed_query(self.embeddings(), query).await?;
+        self.similarity_search_with_score_by_vector(&embedding, k, filter, score_threshold)
+            .await
+    }
+
+    /// similarity_searchと同じ順序で、id・embeddingも含めたレコードを返す(デバッグ用)
+    async fn similarity_search_with_vector(
+        &self,
+        query: &str,
+        k: Option<usize>,
+        filter: Option<&Filter>,
+    ) -> anyhow::Result<Vec<VectorRecord>> {
+        let embedding = embed_query(self.embeddings(), query).await?;
+        let records = self
+            .similarity_search_records_by_vector(&embedding, k, filter, None)
+            .await?;
+        Ok(records.into_iter().map(|(record, _)| record).collect())
+    }
+
+    /// `max_marginal_relevance_search_by_vector`のquery版
+    async fn max_marginal_relevance_search(
+        &self,
+        query: &str,
+        k: Option<usize>,
+        fetch_k: Option<usize>,
+        lambda_mult: Option<f32>,
+        filter: Option<&Filter>,
+    ) -> anyhow::Result<Vec<Document>> {
+        let embedding = embed_query(self.embeddings(), query).await?;
+        self.max_marginal_relevance_search_by_vector(&embedding, k, fetch_k, lambda_mult, filter)
+            .await
+    }
+
+    /// documentを追加し、そのidを返す
+    ///
+    /// `ids`を指定した場合は`documents`と同じ順にidとして使い、既に同じidのレコードがあれば置き換える(upsert)。
+    /// 指定しない場合は新しくUUIDを割り当てる
+    ///
+    /// エラーになった場合は`documents`のどれも追加しない
+    async fn add_document(
+        &self,
+        documents: Vec<Document>,
+        ids: Option<Vec<String>>,
+    ) -> anyhow::Result<Vec<String>>;
+    /// 指定したidのレコードを`ids`の順に返す。存在しないidは無視する
+    async fn get_by_ids(&self, ids: &[String]) -> anyhow::Result<Vec<VectorRecord>>;
+    /// 指定したidのレコードを削除する
+    async fn delete_document(&self, ids: Vec<String>) -> anyhow::Result<DeleteResult>;
+    /// metadataが`filter`にマッチするレコードをすべて削除する
+    ///
+    /// 同じsourceから作ったchunkをまとめて削除する場合などに使う
+    async fn delete_by_filter(&self, filter: &Filter) -> anyhow::Result<DeleteResult>;
+    /// すべてのレコードを削除する
+    async fn clear(&self) -> anyhow::Result<()>;
+}
+
+/// `add_document`で使うidを決める。指定されていない場合は新しくUUIDを割り当てる
+fn resolve_ids(ids: Option<Vec<String>>, len: usize) -> anyhow::Result<Vec<String>> {
+    match ids {
+        Some(ids) if ids.len() != len => anyhow::bail!(
+            "number of ids ({}) does not match number of documents ({})",
+            ids.len(),
+            len
+        ),
+        Some(ids) => Ok(ids),
+        None => Ok((0..len).map(|_| uuid::Uuid::new_v4().to_string()).collect()),
+    }
+}
+
+/// `add_document`で1回に追加するembeddingを、storeに書き込む前に確かめる
+///
+/// 数が`documents`と一致し、すべて同じ次元数であればその次元数を返す(空の場合はNone)。
+/// 途中で失敗して一部だけ追加された状態にならないよう、書き込みを始める前に呼ぶ
+fn check_embeddings(embeddings: &[Vec<f32>], documents: usize) -> anyhow::Result<Option<usize>> {
+    if embeddings.len() != documents {
+        anyhow::bail!(
+            "expected {} embeddings, but got {}",
+            documents,
+            embeddings.len()
+        );
+    }
+    let dimension = embeddings.first().map(Vec::len);
+    if let Some(embedding) = embeddings
+        .iter()
+        .find(|embedding| Some(embedding.len()) != dimension)
+    {
+        anyhow::bail!(
+            "embedding dimension mismatch within batch: {:?} and {}",
+            dimension,
+            embedding.len()
+        );
+    }
+    Ok(dimension)
+}
+
+async fn embed_query<E: Embeddings>(
+    embeddings: Option<Arc<E>>,
+    query: &str,
+) -> anyhow::Result<Vec<f32>> {
+    embeddings
+        .ok_or(anyhow::anyhow!("embeddings is None"))?
+        .embed_query(query)
+        .await
 }
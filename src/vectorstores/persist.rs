@@ -0,0 +1,140 @@
+//! # VectorStoreの保存形式
+//!
+//! 1ファイルのJSONで、中身は以下の通り(version 1)
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "model": "text-embedding-ada-002",
+//!   "dimension": 1536,
+//!   "distance_strategy": "Cosine",
+//!   "records": [
+//!     {
+//!       "id": "6f1c2b1e-...",
+//!       "document": {
+//!         "page_content": "...",
+//!         "lookup_str": "",
+//!         "lookup_index": 0,
+//!         "metadata": { "source": "https://..." }
+//!       },
+//!       "embedding": [0.0123, -0.0456, ...]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! - `version`: 形式のバージョン。互換性のない変更をした場合に上げる
+//! - `model`: embeddingを生成したモデル(`Embeddings::model_name`)
+//! - `dimension`: embeddingの次元数。レコードが無く次元数もわからない場合はnull
+//! - `distance_strategy`: スコアの計算方法(`DistanceStrategy`)。無い場合は`Cosine`
+//! - `records`: 保存されているレコード。順序に意味はない
+
+use super::*;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PersistedStore {
+    pub version: u32,
+    pub model: String,
+    pub dimension: Option<usize>,
+    /// 後から追加したので、無いファイルも読めるようにデフォルトを使う
+    #[serde(default)]
+    pub distance_strategy: DistanceStrategy,
+    pub records: Vec<VectorRecord>,
+}
+
+impl PersistedStore {
+    pub fn new<E: Embeddings>(
+        embeddings: &E,
+        records: Vec<VectorRecord>,
+        distance_strategy: DistanceStrategy,
+    ) -> anyhow::Result<Self> {
+        let dimension = records
+            .first()
+            .map(|record| record.embedding.len())
+            .or(embeddings.dimension());
+        let store = Self {
+            version: FORMAT_VERSION,
+            model: embeddings.model_name().to_string(),
+            dimension,
+            distance_strategy,
+            records,
+        };
+        store.validate_records()?;
+        Ok(store)
+    }
+
+    /// 一時ファイルに書き込んでからrenameするので、書き込み途中のファイルが残ることはない
+    pub fn write(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        drop(writer);
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let store: Self = serde_json::from_reader(reader)?;
+        if store.version != FORMAT_VERSION {
+            anyhow::bail!(
+                "unsupported vector store format version: {} (expected {})",
+                store.version,
+                FORMAT_VERSION
+            );
+        }
+        store.validate_records()?;
+        Ok(store)
+    }
+
+    /// 保存されているembeddingが`embeddings`で生成したものとして扱えるか確認する
+    pub fn check_compatible<E: Embeddings>(&self, embeddings: &E) -> anyhow::Result<()> {
+        if self.model != embeddings.model_name() {
+            anyhow::bail!(
+                "embedding model mismatch: stored {}, configured {}",
+                self.model,
+                embeddings.model_name()
+            );
+        }
+        if let (Some(stored), Some(configured)) = (self.dimension, embeddings.dimension()) {
+            if stored != configured {
+                anyhow::bail!(
+                    "embedding dimension mismatch: stored {}, configured {}",
+                    stored,
+                    configured
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_records(&self) -> anyhow::Result<()> {
+        let Some(dimension) = self.dimension else {
+            if self.records.is_empty() {
+                return Ok(());
+            }
+            anyhow::bail!("dimension is missing");
+        };
+        if let Some(record) = self
+            .records
+            .iter()
+            .find(|record| record.embedding.len() != dimension)
+        {
+            anyhow::bail!(
+                "record {} has dimension {}, expected {}",
+                record.id,
+                record.embedding.len(),
+                dimension
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+/// # Quantization
+///
+/// InMemoryVectorStoreがembeddingをどの精度で保持するか
+///
+/// `rescore`が`true`の場合は元のf32のベクトルも保持し、量子化したベクトルで多めに集めた候補を
+/// 元のベクトルで計算し直してから上位`k`件を返す。スコアは量子化しない場合と同じになるが、メモリは減らない
+///
+/// `rescore`が`false`の場合は元のベクトルを捨てるのでメモリが減るが、スコアも結果の順序も近似になる。
+/// 検索結果や保存されるembeddingも量子化したものから復元した値になる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quantization {
+    /// f32のまま保持する
+    #[default]
+    None,
+    /// 行ごとに最大絶対値で割ってi8にする。メモリは約1/4
+    Int8 { rescore: bool },
+    /// 各要素の符号だけを1bitで持つ。メモリは約1/32
+    Binary { rescore: bool },
+}
+
+impl Quantization {
+    /// 元のf32のベクトルを保持するかどうか
+    pub fn keeps_originals(&self) -> bool {
+        match self {
+            Quantization::None => true,
+            Quantization::Int8 { rescore } | Quantization::Binary { rescore } => *rescore,
+        }
+    }
+
+    /// rescoreする場合に、量子化したベクトルで集める候補数の`k`に対する倍率
+    ///
+    /// binaryは近似が粗いので多めに集める
+    pub fn oversampling(&self) -> usize {
+        match self {
+            Quantization::None => 1,
+            Quantization::Int8 { .. } => 4,
+            Quantization::Binary { .. } => 10,
+        }
+    }
+}
+
+/// 量子化したベクトルを行として連続して並べたもの
+pub(crate) enum QuantizedVectors {
+    Int8 {
+        dimension: usize,
+        codes: Vec<i8>,
+        /// 行ごとの`code * scale`で元の値に戻る
+        scales: Vec<f32>,
+    },
+    Binary {
+        dimension: usize,
+        /// 1行あたりの`u64`の数
+        words: usize,
+        bits: Vec<u64>,
+    },
+}
+
+/// 検索時にqueryを量子化したベクトルと比較できる形にしたもの
+pub(crate) enum QuantizedQuery<'a> {
+    Int8(&'a [f32]),
+    Binary(Vec<u64>),
+}
+
+impl QuantizedVectors {
+    pub fn new(quantization: Quantization, dimension: usize) -> Option<Self> {
+        match quantization {
+            Quantization::None => None,
+            Quantization::Int8 { .. } => Some(QuantizedVectors::Int8 {
+                dimension,
+                codes: Vec::new(),
+                scales: Vec::new(),
+            }),
+            Quantization::Binary { .. } => Some(QuantizedVectors::Binary {
+                dimension,
+                words: dimension.div_ceil(64),
+                bits: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn push(&mut self, vector: &[f32]) {
+        match self {
+            QuantizedVectors::Int8 { codes, scales, .. } => {
+                let max = vector.iter().fold(0.0f32, |max, v| max.max(v.abs()));
+                let scale = max / i8::MAX as f32;
+                codes.extend(vector.iter().map(|v| {
+                    if scale == 0.0 {
+                        0
+                    } else {
+                        (v / scale).round() as i8
+                    }
+                }));
+                scales.push(scale);
+            }
+            QuantizedVectors::Binary { bits, .. } => bits.extend(sign_bits(vector)),
+        }
+    }
+
+    /// 最後の行を`row`の位置に移動して詰める
+    pub fn swap_remove(&mut self, row: usize) {
+        match self {
+            QuantizedVectors::Int8 {
+                dimension,
+                codes,
+                scales,
+            } => {
+                swap_remove_row(codes, *dimension, row);
+                scales.swap_remove(row);
+            }
+            QuantizedVectors::Binary { words, bits, .. } => swap_remove_row(bits, *words, row),
+        }
+    }
+
+    pub fn query<'a>(&self, query: &'a [f32]) -> QuantizedQuery<'a> {
+        match self {
+            QuantizedVectors::Int8 { .. } => QuantizedQuery::Int8(query),
+            QuantizedVectors::Binary { .. } => QuantizedQuery::Binary(sign_bits(query).collect()),
+        }
+    }
+
+    /// queryと`row`の内積の推定値
+    ///
+    /// binaryの場合は、符号の一致しない割合からcosine similarityを推定し、ノルムを掛けて内積にする
+    pub fn estimate_dot(
+        &self,
+        query: &QuantizedQuery,
+        query_norm: f32,
+        row: usize,
+        row_norm: f32,
+    ) -> f32 {
+        match (self, query) {
+            (
+                QuantizedVectors::Int8 {
+                    dimension,
+                    codes,
+                    scales,
+                },
+                QuantizedQuery::Int8(query),
+            ) => {
+                let codes = &codes[row * dimension..(row + 1) * dimension];
+                let dot: f32 = query.iter().zip(codes).map(|(q, c)| q * *c as f32).sum();
+                dot * scales[row]
+            }
+            (
+                QuantizedVectors::Binary {
+                    dimension,
+                    words,
+                    bits,
+                },
+                QuantizedQuery::Binary(query),
+            ) => {
+                let hamming: u32 = bits[row * words..(row + 1) * words]
+                    .iter()
+                    .zip(query)
+                    .map(|(a, b)| (a ^ b).count_ones())
+                    .sum();
+                let cosine = (std::f32::consts::PI * hamming as f32 / *dimension as f32).cos();
+                cosine * query_norm * row_norm
+            }
+            _ => unreachable!("query is quantized by the same QuantizedVectors"),
+        }
+    }
+
+    /// 量子化したベクトルから元のベクトルを復元する
+    ///
+    /// binaryは符号しか残っていないので、ノルムが`norm`になるように各要素を同じ大きさにする
+    pub fn dequantize(&self, row: usize, norm: f32) -> Vec<f32> {
+        match self {
+            QuantizedVectors::Int8 {
+                dimension,
+                codes,
+                scales,
+            } => codes[row * dimension..(row + 1) * dimension]
+                .iter()
+                .map(|c| *c as f32 * scales[row])
+                .collect(),
+            QuantizedVectors::Binary {
+                dimension,
+                words,
+                bits,
+            } => {
+                let magnitude = norm / (*dimension as f32).sqrt();
+                let bits = &bits[row * words..(row + 1) * words];
+                (0..*dimension)
+                    .map(|i| {
+                        if bits[i / 64] >> (i % 64) & 1 == 1 {
+                            magnitude
+                        } else {
+                            -magnitude
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// 正の要素を1、それ以外を0として64要素ずつ`u64`に詰める
+fn sign_bits(vector: &[f32]) -> impl Iterator<Item = u64> + '_ {
+    vector.chunks(64).map(|chunk| {
+        chunk
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| **v > 0.0)
+            .fold(0u64, |word, (i, _)| word | 1 << i)
+    })
+}
+
+fn swap_remove_row<T: Copy>(values: &mut Vec<T>, width: usize, row: usize) {
+    let last = values.len() / width - 1;
+    if row != last {
+        values.copy_within(last * width..(last + 1) * width, row * width);
+    }
+    values.truncate(last * width);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vectorstores::utils;
+
+    #[test]
+    fn test_int8_round_trip() {
+        let vector = [0.5, -1.0, 0.25, 0.0, 0.9];
+        let mut quantized =
+            QuantizedVectors::new(Quantization::Int8 { rescore: false }, 5).unwrap();
+        quantized.push(&vector);
+        quantized.push(&[0.0; 5]);
+
+        let restored = quantized.dequantize(0, utils::norm(&vector));
+        for (a, b) in vector.iter().zip(&restored) {
+            assert!((a - b).abs() <= 1.0 / 254.0 + 1e-6);
+        }
+        let query = [1.0, 2.0, 3.0, 4.0, 5.0];
+        let estimated = quantized.estimate_dot(&quantized.query(&query), 0.0, 0, 0.0);
+        assert!((estimated - utils::dot_product(&query, &vector)).abs() < 0.05);
+        assert_eq!(quantized.dequantize(1, 0.0), vec![0.0; 5]);
+    }
+
+    #[test]
+    fn test_binary_estimate_and_swap_remove() {
+        let dimension = 70;
+        let a: Vec<f32> = (0..dimension).map(|i| i as f32 - 35.0).collect();
+        let opposite: Vec<f32> = a.iter().map(|v| -v).collect();
+        let mut quantized =
+            QuantizedVectors::new(Quantization::Binary { rescore: false }, dimension).unwrap();
+        quantized.push(&opposite);
+        quantized.push(&a);
+        let query = quantized.query(&a);
+
+        // 符号が全部一致すればcosine 1、全部逆(0は両方とも0になるので1要素を除く)ならほぼ-1
+        assert!((quantized.estimate_dot(&query, 1.0, 1, 1.0) - 1.0).abs() < 1e-6);
+        assert!(quantized.estimate_dot(&query, 1.0, 0, 1.0) < -0.99);
+
+        let restored = quantized.dequantize(1, 2.0);
+        assert!((utils::norm(&restored) - 2.0).abs() < 1e-4);
+        assert!(restored
+            .iter()
+            .zip(&a)
+            .all(|(r, v)| (*r > 0.0) == (*v > 0.0)));
+
+        quantized.swap_remove(0);
+        assert!((quantized.estimate_dot(&query, 1.0, 0, 1.0) - 1.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,275 @@
+/// cosine similarity
+///
+/// どちらかがゼロベクトルの場合は0を返す
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let norm = dot_product(a, a).sqrt() * dot_product(b, b).sqrt();
+    if norm == 0.0 {
+        return 0.0;
+    }
+    dot_product(a, b) / norm
+}
+
+/// 8要素ずつ別々のアキュムレータに足し込むことで、コンパイラがSIMD命令に自動ベクトル化できるようにしている
+pub fn dot_product(a: &[f32], b: &[f32]) -> f32 {
+    let len = a.len().min(b.len());
+    let (a, b) = (&a[..len], &b[..len]);
+    let mut sums = [0.0f32; 8];
+    let a_chunks = a.chunks_exact(8);
+    let b_chunks = b.chunks_exact(8);
+    let tail: f32 = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(a, b)| a * b)
+        .sum();
+    for (a, b) in a_chunks.zip(b_chunks) {
+        for i in 0..8 {
+            sums[i] += a[i] * b[i];
+        }
+    }
+    sums.iter().sum::<f32>() + tail
+}
+
+pub fn norm(a: &[f32]) -> f32 {
+    dot_product(a, a).sqrt()
+}
+
+pub fn euclidean_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(a, b)| (a - b) * (a - b))
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// # DistanceStrategy
+///
+/// ベクトル同士の近さの測り方
+///
+/// どの方法でも、スコア(relevance)は大きいほど似ていることを表し、0から1の範囲に正規化される。
+/// 検索結果の順位はスコアではなく`similarity`で決める
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub enum DistanceStrategy {
+    /// `(1 + cosine similarity) / 2`。範囲は[0, 1]
+    #[default]
+    Cosine,
+    /// `(1 + dot product) / 2`。正規化済み(ノルムが1)のembedding向けで、範囲は[0, 1]に丸める
+    DotProduct,
+    /// `1 / (1 + euclidean distance)`。範囲は(0, 1]
+    EuclideanDistance,
+    /// `sigmoid(dot product)`。ノルムに意味があるembedding向けで、範囲は(0, 1)
+    MaxInnerProduct,
+}
+
+impl DistanceStrategy {
+    /// 順位付けに使う類似度。大きいほど似ている
+    ///
+    /// cosine similarity、内積、またはユークリッド距離の符号を反転したもので、範囲は正規化しない。
+    /// スコア(relevance)は[0, 1]に収めるために飽和する(`MaxInnerProduct`のsigmoidはf32では内積が17程度を超えると
+    /// 1になる)ので、並べ替えやグラフの構築にはこちらを使い、結果を返すときに`relevance_from_similarity`で変換する
+    pub fn similarity(&self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            DistanceStrategy::Cosine => cosine_similarity(a, b),
+            DistanceStrategy::DotProduct | DistanceStrategy::MaxInnerProduct => dot_product(a, b),
+            DistanceStrategy::EuclideanDistance => -euclidean_distance(a, b),
+        }
+    }
+
+    /// 内積と、事前に計算しておいたそれぞれのノルムから`similarity`を計算する
+    ///
+    /// `similarity`と同じ値になるが、ノルムを毎回計算しなくて済む
+    pub fn similarity_from_dot(&self, dot: f32, a_norm: f32, b_norm: f32) -> f32 {
+        match self {
+            DistanceStrategy::Cosine => {
+                let norm = a_norm * b_norm;
+                if norm == 0.0 {
+                    0.0
+                } else {
+                    dot / norm
+                }
+            }
+            DistanceStrategy::DotProduct | DistanceStrategy::MaxInnerProduct => dot,
+            DistanceStrategy::EuclideanDistance => {
+                // |a - b|^2 = |a|^2 + |b|^2 - 2a・b
+                let squared = a_norm * a_norm + b_norm * b_norm - 2.0 * dot;
+                -squared.max(0.0).sqrt()
+            }
+        }
+    }
+
+    /// `similarity`をスコア(relevance)に変換する
+    pub fn relevance_from_similarity(&self, similarity: f32) -> f32 {
+        match self {
+            DistanceStrategy::Cosine => (1.0 + similarity) / 2.0,
+            DistanceStrategy::DotProduct => ((1.0 + similarity) / 2.0).clamp(0.0, 1.0),
+            DistanceStrategy::EuclideanDistance => 1.0 / (1.0 - similarity),
+            DistanceStrategy::MaxInnerProduct => 1.0 / (1.0 + (-similarity).exp()),
+        }
+    }
+
+    /// `a`と`b`のスコア(relevance)を返す
+    pub fn relevance_score(&self, a: &[f32], b: &[f32]) -> f32 {
+        self.relevance_from_similarity(self.similarity(a, b))
+    }
+
+    /// 内積と、事前に計算しておいたそれぞれのノルムからスコアを計算する
+    pub fn relevance_score_from_dot(&self, dot: f32, a_norm: f32, b_norm: f32) -> f32 {
+        self.relevance_from_similarity(self.similarity_from_dot(dot, a_norm, b_norm))
+    }
+}
+
+/// # Maximal Marginal Relevance
+///
+/// queryとの類似度が高く、かつ既に選んだものとは似ていないembeddingを順に`k`個選び、
+/// そのindexを選んだ順に返す
+///
+/// `lambda_mult`は0から1の値で、1に近いほどqueryとの類似度を、0に近いほど多様性を重視する
+///
+/// https://github.com/hwchase17/langchain/blob/c2d1d903fa35b91018b4d777db2b008fcbaa9fbc/langchain/vectorstores/utils.py#L14
+pub fn maximal_marginal_relevance(
+    query_embedding: &[f32],
+    embeddings: &[&[f32]],
+    lambda_mult: f32,
+    k: usize,
+) -> Vec<usize> {
+    let k = k.min(embeddings.len());
+    if k == 0 {
+        return Vec::new();
+    }
+    let query_similarities: Vec<f32> = embeddings
+        .iter()
+        .map(|embedding| cosine_similarity(query_embedding, embedding))
+        .collect();
+    // 各候補について、選択済みのものとの類似度の最大値
+    let mut redundancies = vec![f32::NEG_INFINITY; embeddings.len()];
+    let mut selected: Vec<usize> = Vec::with_capacity(k);
+
+    while selected.len() < k {
+        let mut best: Option<(usize, f32)> = None;
+        for (i, query_similarity) in query_similarities.iter().enumerate() {
+            if selected.contains(&i) {
+                continue;
+            }
+            let redundancy = if selected.is_empty() {
+                0.0
+            } else {
+                redundancies[i]
+            };
+            let score = lambda_mult * query_similarity - (1.0 - lambda_mult) * redundancy;
+            if best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((i, score));
+            }
+        }
+        let Some((chosen, _)) = best else {
+            break;
+        };
+        selected.push(chosen);
+        for (i, redundancy) in redundancies.iter_mut().enumerate() {
+            *redundancy = redundancy.max(cosine_similarity(embeddings[i], embeddings[chosen]));
+        }
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn test_relevance_score() {
+        let a = [1.0, 0.0];
+        let b = [0.0, 1.0];
+        let c = [-1.0, 0.0];
+        for strategy in [
+            DistanceStrategy::Cosine,
+            DistanceStrategy::DotProduct,
+            DistanceStrategy::EuclideanDistance,
+            DistanceStrategy::MaxInnerProduct,
+        ] {
+            let same = strategy.relevance_score(&a, &a);
+            let orthogonal = strategy.relevance_score(&a, &b);
+            let opposite = strategy.relevance_score(&a, &c);
+            assert!(same > orthogonal && orthogonal > opposite, "{:?}", strategy);
+            assert!((0.0..=1.0).contains(&same) && (0.0..=1.0).contains(&opposite));
+        }
+        assert_eq!(DistanceStrategy::Cosine.relevance_score(&a, &a), 1.0);
+        assert_eq!(DistanceStrategy::Cosine.relevance_score(&a, &b), 0.5);
+        assert_eq!(DistanceStrategy::Cosine.relevance_score(&a, &c), 0.0);
+        assert_eq!(
+            DistanceStrategy::EuclideanDistance.relevance_score(&a, &a),
+            1.0
+        );
+        assert_eq!(
+            DistanceStrategy::MaxInnerProduct.relevance_score(&a, &b),
+            0.5
+        );
+    }
+
+    #[test]
+    fn test_relevance_score_from_dot() {
+        let a: Vec<f32> = (0..19).map(|i| i as f32 * 0.1).collect();
+        let b: Vec<f32> = (0..19).map(|i| 1.0 - i as f32 * 0.05).collect();
+        for strategy in [
+            DistanceStrategy::Cosine,
+            DistanceStrategy::DotProduct,
+            DistanceStrategy::EuclideanDistance,
+            DistanceStrategy::MaxInnerProduct,
+        ] {
+            let expected = strategy.relevance_score(&a, &b);
+            let actual = strategy.relevance_score_from_dot(dot_product(&a, &b), norm(&a), norm(&b));
+            assert!((expected - actual).abs() < 1e-5, "{:?}", strategy);
+            let expected = strategy.similarity(&a, &b);
+            let actual = strategy.similarity_from_dot(dot_product(&a, &b), norm(&a), norm(&b));
+            assert!((expected - actual).abs() < 1e-4, "{:?}", strategy);
+        }
+    }
+
+    #[test]
+    fn test_similarity_does_not_saturate() {
+        let a = [5.0, 0.0];
+        let b = [6.0, 0.0];
+        for strategy in [
+            DistanceStrategy::DotProduct,
+            DistanceStrategy::MaxInnerProduct,
+        ] {
+            // 内積が20を超えるとスコアは同じ値になるが、similarityでは区別できる
+            assert_eq!(
+                strategy.relevance_score(&a, &a),
+                strategy.relevance_score(&a, &b)
+            );
+            assert!(strategy.similarity(&a, &b) > strategy.similarity(&a, &a));
+        }
+    }
+
+    #[test]
+    fn test_maximal_marginal_relevance() {
+        let query = [1.0, 1.0];
+        let a: &[f32] = &[1.0, 0.2];
+        let a_dup: &[f32] = &[1.0, 0.25];
+        let b: &[f32] = &[0.1, 1.0];
+        let embeddings = [a, a_dup, b];
+
+        // 類似度だけを見ると似たもの同士が選ばれる
+        assert_eq!(
+            maximal_marginal_relevance(&query, &embeddings, 1.0, 2),
+            vec![1, 0]
+        );
+        // 多様性を考慮すると重複は避けられる
+        assert_eq!(
+            maximal_marginal_relevance(&query, &embeddings, 0.5, 2),
+            vec![1, 2]
+        );
+        assert_eq!(
+            maximal_marginal_relevance(&query, &embeddings, 0.5, 10).len(),
+            3
+        );
+        assert!(maximal_marginal_relevance(&query, &[], 0.5, 2).is_empty());
+    }
+}